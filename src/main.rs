@@ -26,20 +26,245 @@ use std::fs;
 use std::io::Write;
 use std::{env, vec};
 use std::collections::HashMap;
-use image::Rgb;
+use std::rc::Rc;
+use image::{Rgb, Rgba};
 
 const IMG_R: usize = 0;
 const IMG_G: usize = 1;
 const IMG_B: usize = 2;
 const IMG_A: usize = 3;
 
+// File name extensions of the image formats that may be read.
+const INPUT_EXTENSIONS: [&str; 14] = [
+    "png", "bmp", "gif", "tga", "qoi", "pcx", "tif", "tiff", "pbm", "pgm", "ppm", "pam",
+    "ase", "aseprite"
+];
+const ASEPRITE_EXTENSIONS: [&str; 2] = ["ase", "aseprite"];
+
+// How an 8-bit color component is reduced to a 2-bit color component.
+#[derive(Debug, Clone, PartialEq)]
+enum Quantize {
+    Truncate,           // keep the upper 2 bits
+    Nearest,            // pick the nearest widened level (00H, 55H, AAH, FFH)
+    Thresholds([u8; 3]) // minimum 8-bit values for levels 1, 2, and 3
+}
+
+// How the quantization error of one pixel is spread to its neighbors.
+#[derive(Debug, Clone, PartialEq)]
+enum Dither {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    SierraLite,
+    Ordered(i32) // Bayer matrix size (2, 4, or 8)
+}
+
+// How the colors of a file are reduced, when it has too many colors.
+#[derive(Debug, Clone, PartialEq)]
+enum Reduce {
+    None,
+    MedianCut,
+    KMeans
+}
+
+// What semi-transparent pixels of an indexed file are composited against.
+#[derive(Debug, Clone, PartialEq)]
+enum Background {
+    None,               // use the raw RGB of every pixel
+    Color(Rgb<u8>),     // a given 24-bit color
+    Auto                // the most common opaque border color of the image
+}
+
+// How an input image is scaled, before it is converted.
+#[derive(Debug, Clone, PartialEq)]
+enum Scale {
+    None,           // the image is used at its own size
+    Stretch,        // scaled to exactly the output size
+    Fit,            // scaled to fit inside the output size, keeping the aspect ratio
+    Fill,           // scaled to cover the output size, keeping the aspect ratio
+    Factor(f64)     // scaled by the given factor
+}
+
+// Which filter is used when scaling an input image.
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Nearest,    // nearest neighbor (keeps hard pixel edges)
+    Box,        // area averaging
+    Bilinear,
+    Lanczos     // Lanczos with a radius of 3
+}
+
+// How an input image is aligned with the output image, along one axis.
+#[derive(Debug, Clone, PartialEq)]
+enum Align {
+    Start,  // left or top edges line up
+    Center, // centers line up
+    End     // right or bottom edges line up
+}
+
+// Which point of the input image is placed at the same point of the output image.
+#[derive(Debug, Clone, PartialEq)]
+struct Anchor {
+    pub horizontal: Align,
+    pub vertical: Align
+}
+
+// What is done with the frames of an animated file (GIF or APNG).
+#[derive(Debug, Clone, PartialEq)]
+enum Animation {
+    First,  // only the first frame is used
+    Files,  // each frame is written to its own (numbered) output file
+    Joined  // all frames are written, one after another, to one output file
+}
+
+// One frame of an animated file, processed as a file of its own.
+#[derive(Debug, Clone, PartialEq)]
+struct FrameInfo {
+    pub index: usize,               // frame number, starting from zero
+    pub count: usize,               // number of frames in the file
+    pub delay: u32,                 // display time of the frame, in milliseconds
+    pub cell: Option<(u32, u32)>    // position of the cell, when a sheet is sliced
+}
+
+// Where the trimmed region of an image lies within the whole (untrimmed) image.
+#[derive(Debug, Clone, PartialEq)]
+struct Trim {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub original_width: u32,
+    pub original_height: u32
+}
+
+// The size limits of an atlas, and the padding between the images packed into it (in pixels).
+#[derive(Debug, Clone, PartialEq)]
+struct AtlasLimits {
+    pub width: u32,
+    pub height: u32,
+    pub padding: u32
+}
+
+// Where one image is placed in an atlas.
+#[derive(Debug, Clone, PartialEq)]
+struct AtlasRect {
+    pub position: usize,                        // index of the packed image in the list of files
+    pub name: String,
+    pub label: String,                          // assembler label for the rectangle
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub trim: Option<(i32, i32, u32, u32)>      // offset in, and size of, the untrimmed image
+}
+
+// The region of an input image that is converted (instead of the whole image), and an
+// optional name for its output file.
+#[derive(Debug, Clone, PartialEq)]
+struct Source {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub name: Option<String>
+}
+
+// How a sprite sheet is sliced into cells (all sizes are in pixels).
+#[derive(Debug, Clone, PartialEq)]
+struct Slice {
+    pub width: u32,
+    pub height: u32,
+    pub margin: u32,    // space around the whole sheet
+    pub spacing: u32    // space between neighboring cells
+}
+
+impl Dither {
+    // Returns the error diffusion kernel, as (dx, dy, weight) entries, plus the divisor.
+    pub fn kernel(&self) -> (&'static [(i32, i32, i32)], i32) {
+        match self {
+            Dither::None | Dither::Ordered(_) => (&[], 1),
+            Dither::FloydSteinberg => (&[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)], 16),
+            Dither::Atkinson => (&[(1, 0, 1), (2, 0, 1), (-1, 1, 1), (0, 1, 1), (1, 1, 1), (0, 2, 1)], 8),
+            Dither::SierraLite => (&[(1, 0, 2), (-1, 1, 1), (0, 1, 1)], 4)
+        }
+    }
+}
+
+// How the difference between two colors is measured, when matching colors.
+#[derive(Debug, Clone, PartialEq)]
+enum Metric {
+    Rgb,        // Euclidean distance in RGB space
+    Redmean,    // weighted RGB distance, approximating human perception
+    Lab76,      // CIELAB delta E (CIE 1976)
+    Lab2000     // CIELAB delta E (CIEDE2000)
+}
+
+// How newly allocated palette indexes are ordered.
+#[derive(Debug, Clone, PartialEq)]
+enum Order {
+    Appearance,             // first appearance, in file order and scan order
+    Frequency,              // most pixels first
+    Luminance,              // darkest first
+    Hue,                    // grays first, then by hue angle
+    Explicit(Vec<Rgb<u8>>)  // listed colors first, then by appearance
+}
+
+// Options that apply to the whole run, rather than to one input.
+#[derive(Debug, Clone)]
+struct GlobalParameters {
+    pub optimize: Reduce,
+    pub metric: Metric,
+    pub palette: Option<Vec<Option<Rgb<u8>>>>,
+    pub previous: Option<Vec<Option<Rgb<u8>>>>,
+    pub nearest: bool,
+    pub order: Order,
+    pub pins: Vec<(usize, Rgb<u8>)>,
+    pub reserved: Vec<usize>
+}
+
+impl GlobalParameters {
+    pub fn new() -> Self {
+        Self {
+            optimize: Reduce::None,
+            metric: Metric::Rgb,
+            palette: None,
+            previous: None,
+            nearest: false,
+            order: Order::Appearance,
+            pins: vec![],
+            reserved: vec![]
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct DirParameters {
     pub width: usize,
     pub height: usize,
     pub bpp: u8,
     pub no_output: bool,
+    pub file_palette: bool,
     pub vapor: bool,
+    pub quantize: Quantize,
+    pub dither: Dither,
+    pub alpha_quantize: Quantize,
+    pub alpha_dither: Dither,
+    pub strength: i32,
+    pub reduce: Reduce,
+    pub alpha_threshold: u8,
+    pub chroma_key: Option<Rgb<u8>>,
+    pub background: Background,
+    pub extensions: Vec<String>,
+    pub animation: Animation,
+    pub slice: Option<Slice>,
+    pub trim: bool,
+    pub atlas: Option<AtlasLimits>,
+    pub scale: Scale,
+    pub filter: Filter,
+    pub anchor: Anchor,
+    pub offset: (i32, i32),
+    pub source: Option<Source>,
+    pub source_palette: bool,
     pub path: String
 }
 
@@ -50,7 +275,28 @@ impl DirParameters {
             height: 0,
             bpp: 0,
             no_output: false,
+            file_palette: false,
             vapor: false,
+            quantize: Quantize::Truncate,
+            dither: Dither::None,
+            alpha_quantize: Quantize::Truncate,
+            alpha_dither: Dither::None,
+            strength: 100,
+            reduce: Reduce::None,
+            alpha_threshold: 0,
+            chroma_key: None,
+            background: Background::None,
+            extensions: INPUT_EXTENSIONS.iter().map(|extension| extension.to_string()).collect(),
+            animation: Animation::First,
+            slice: None,
+            trim: false,
+            atlas: None,
+            scale: Scale::None,
+            filter: Filter::Nearest,
+            anchor: Anchor { horizontal: Align::Center, vertical: Align::Center },
+            offset: (0, 0),
+            source: None,
+            source_palette: false,
             path: String::new()        
         }
     }
@@ -68,11 +314,41 @@ struct FileParameters {
     pub height: usize,
     pub bpp: u8,
     pub no_output: bool,
+    pub file_palette: bool,
     pub vapor: bool,
+    pub quantize: Quantize,
+    pub dither: Dither,
+    pub alpha_quantize: Quantize,
+    pub alpha_dither: Dither,
+    pub strength: i32,
+    pub reduce: Reduce,
+    pub alpha_threshold: u8,
+    pub chroma_key: Option<Rgb<u8>>,
+    pub background: Background,
+    pub animation: Animation,
+    pub slice: Option<Slice>,
+    pub trim: bool,
+    pub scale: Scale,
+    pub filter: Filter,
+    pub anchor: Anchor,
+    pub offset: (i32, i32),
+    pub source: Option<Source>,
+    pub source_palette: bool,
+    pub scaled: Option<(u32, u32)>,
+    pub trimmed: Option<Trim>,
+    pub packed: bool,
+    pub atlas: Option<Vec<AtlasRect>>,
+    pub frame: Option<FrameInfo>,
+    pub image: Option<Rc<image::DynamicImage>>,   // decoded image of a frame, or sheet of a cell
+    pub tags: Vec<(String, usize, usize)>,          // animation tags (name, first frame, last frame)
+    pub aseprite_palette: Option<Vec<[u8; 4]>>,     // palette of an indexed Aseprite file
     pub path: String,
     pub size: usize,
     pub max_colors: usize,
-    pub colors: HashMap<Rgb<u8>, u8>
+    pub colors: HashMap<Rgb<u8>, u8>,
+    pub histogram: Vec<(Rgb<u8>, usize)>,
+    pub representatives: HashMap<Rgb<u8>, [f64; 3]>,   // mean 8-bit pixel color of each histogram color
+    pub remap: HashMap<Rgb<u8>, Rgb<u8>>
 }
 
 impl FileParameters {
@@ -82,13 +358,88 @@ impl FileParameters {
             height: params.height,
             bpp: params.bpp,
             no_output: params.no_output,
+            file_palette: params.file_palette,
             vapor: params.vapor,
+            quantize: params.quantize.clone(),
+            dither: params.dither.clone(),
+            alpha_quantize: params.alpha_quantize.clone(),
+            alpha_dither: params.alpha_dither.clone(),
+            strength: params.strength,
+            reduce: params.reduce.clone(),
+            alpha_threshold: params.alpha_threshold,
+            chroma_key: params.chroma_key,
+            background: params.background.clone(),
+            animation: params.animation.clone(),
+            slice: params.slice.clone(),
+            trim: params.trim,
+            scale: params.scale.clone(),
+            filter: params.filter.clone(),
+            anchor: params.anchor.clone(),
+            offset: params.offset,
+            source: params.source.clone(),
+            source_palette: params.source_palette,
+            scaled: None,
+            trimmed: None,
+            packed: false,
+            atlas: None,
+            frame: None,
+            image: None,
+            tags: vec![],
+            aseprite_palette: None,
             path: params.path.clone(),
             size: 0,
             max_colors: 0,
-            colors: HashMap::new()
+            colors: HashMap::new(),
+            histogram: vec![],
+            representatives: HashMap::new(),
+            remap: HashMap::new()
         }
     }
+
+    // Returns the name of the input, as shown in the log and in the memory map.
+    pub fn name(&self) -> String {
+        let path = match &self.source {
+            Some(source) => format!("{} (source {},{},{},{})", self.path, source.x, source.y, source.width, source.height),
+            None => self.path.clone()
+        };
+        match &self.frame {
+            Some(FrameInfo { index, cell: Some((x, y)), .. }) => format!("{} (cell {} at {},{})", path, index, x, y),
+            Some(frame) => format!("{} (frame {})", path, frame.index),
+            None => path
+        }
+    }
+
+    // Returns the path of the binary output file, before any frame number is added. A named
+    // source region is written next to the input file, under its own name.
+    pub fn base_path(&self) -> String {
+        match self.source.as_ref().and_then(|source| source.name.as_ref()) {
+            Some(name) => match self.path.rsplit_once('/') {
+                Some((directory, _)) => upcase_filename(&format!("{}/{}.png", directory, name)),
+                None => upcase_filename(&format!("{}.png", name))
+            },
+            None => upcase_filename(&self.path)
+        }
+    }
+
+    // Returns the path of the binary output file.
+    pub fn output_path(&self) -> String {
+        if self.atlas.is_some() {
+            return self.path.clone();
+        }
+        let uc_path = self.base_path();
+        match &self.frame {
+            Some(frame) if self.animation == Animation::Files => {
+                format!("{}_{:03}.BIN", &uc_path[..uc_path.len() - 4], frame.index)
+            },
+            _ => uc_path
+        }
+    }
+
+    // Returns whether the output is appended to the output file of the previous frame
+    // (or cell, since the cells of a sliced sheet are joined unless '-f files' is given).
+    pub fn appends(&self) -> bool {
+        self.animation != Animation::Files && self.frame.as_ref().is_some_and(|frame| frame.index > 0)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -96,7 +447,33 @@ struct Expectations {
     pub width: bool,
     pub height: bool,
     pub file: bool,
-    pub bpp: bool
+    pub bpp: bool,
+    pub quantize: bool,
+    pub dither: bool,
+    pub alpha_quantize: bool,
+    pub alpha_dither: bool,
+    pub background: bool,
+    pub extensions: bool,
+    pub animation: bool,
+    pub slice: bool,
+    pub atlas: bool,
+    pub scale: bool,
+    pub filter: bool,
+    pub anchor: bool,
+    pub offset: bool,
+    pub source: bool,
+    pub strength: bool,
+    pub reduce: bool,
+    pub optimize: bool,
+    pub metric: bool,
+    pub palette: bool,
+    pub palette_match: bool,
+    pub palette_order: bool,
+    pub pin: bool,
+    pub reserve: bool,
+    pub incremental: bool,
+    pub alpha_threshold: bool,
+    pub chroma_key: bool
 }
 
 impl Expectations {
@@ -109,16 +486,30 @@ impl Expectations {
         self.file = true;
     }
 
+    // A global option does not belong to an input, so it leaves unchanged whether
+    // an input (or the current directory, by default) is still expected.
+    pub fn end_global_option(&mut self) {
+        let file = self.file;
+        *self = Expectations::new();
+        self.file = file;
+    }
+
     pub fn anything(&self) -> bool {
-        self.width || self.height || self.bpp
+        self.width || self.height || self.bpp || self.quantize || self.dither || self.strength || self.reduce || self.optimize || self.metric ||
+            self.palette || self.palette_match || self.palette_order ||
+            self.pin || self.reserve || self.incremental ||
+            self.alpha_threshold || self.chroma_key || self.alpha_quantize || self.alpha_dither ||
+            self.background || self.extensions || self.animation || self.slice || self.atlas ||
+            self.scale || self.filter || self.anchor || self.offset || self.source
     }
 }
 
 fn main() {
-    println!("Image to Agon (PNG-to-Agon-binary file convertor) V1.5");
+    println!("Image to Agon (PNG-to-Agon-binary file convertor) V1.6");
 
     // Determine which directories to use.
     let mut directories: Vec<DirParameters> = vec![];
+    let mut global = GlobalParameters::new();
 
     let args: Vec<String> = env::args().collect();
     if args.len() == 1 {
@@ -132,7 +523,10 @@ fn main() {
 
         for a in 1..args.len() {
             let arg = args[a].clone().to_ascii_lowercase();
-            if arg.starts_with("-") && expect.anything() {
+            // An offset may be negative, such as "-4,2", so it may start with a dash.
+            let negative = expect.offset &&
+                arg.strip_prefix('-').is_some_and(|rest| rest.starts_with(|letter: char| letter.is_ascii_digit()));
+            if arg.starts_with("-") && expect.anything() && !negative {
                 println!("ERROR: Missing parameter value");
                 return;
             } else if arg.eq("-w") | arg.eq("-width") {
@@ -143,6 +537,64 @@ fn main() {
                 expect.bpp = true;
             } else if arg.eq("-n") || arg.eq("-nooutput") {
                 params.no_output = true;
+            } else if arg.eq("-pp") || arg.eq("-pngpalette") {
+                params.file_palette = true;
+            } else if arg.eq("-t") || arg.eq("-trim") {
+                params.trim = true;
+            } else if arg.eq("-sp") || arg.eq("-srcpalette") {
+                params.source_palette = true;
+            } else if arg.eq("-q") || arg.eq("-quantize") {
+                expect.quantize = true;
+            } else if arg.eq("-d") || arg.eq("-dither") {
+                expect.dither = true;
+            } else if arg.eq("-s") || arg.eq("-strength") {
+                expect.strength = true;
+            } else if arg.eq("-r") || arg.eq("-reduce") {
+                expect.reduce = true;
+            } else if arg.eq("-o") || arg.eq("-optimize") {
+                expect.optimize = true;
+            } else if arg.eq("-m") || arg.eq("-metric") {
+                expect.metric = true;
+            } else if arg.eq("-p") || arg.eq("-palette") {
+                expect.palette = true;
+            } else if arg.eq("-pm") || arg.eq("-palettematch") {
+                expect.palette_match = true;
+            } else if arg.eq("-po") || arg.eq("-paletteorder") {
+                expect.palette_order = true;
+            } else if arg.eq("-pin") {
+                expect.pin = true;
+            } else if arg.eq("-reserve") {
+                expect.reserve = true;
+            } else if arg.eq("-i") || arg.eq("-incremental") {
+                expect.incremental = true;
+            } else if arg.eq("-a") || arg.eq("-alpha") {
+                expect.alpha_threshold = true;
+            } else if arg.eq("-k") || arg.eq("-chromakey") {
+                expect.chroma_key = true;
+            } else if arg.eq("-aq") || arg.eq("-alphaquantize") {
+                expect.alpha_quantize = true;
+            } else if arg.eq("-ad") || arg.eq("-alphadither") {
+                expect.alpha_dither = true;
+            } else if arg.eq("-bg") || arg.eq("-background") {
+                expect.background = true;
+            } else if arg.eq("-x") || arg.eq("-extensions") {
+                expect.extensions = true;
+            } else if arg.eq("-f") || arg.eq("-frames") {
+                expect.animation = true;
+            } else if arg.eq("-slice") {
+                expect.slice = true;
+            } else if arg.eq("-atlas") {
+                expect.atlas = true;
+            } else if arg.eq("-scale") {
+                expect.scale = true;
+            } else if arg.eq("-filter") {
+                expect.filter = true;
+            } else if arg.eq("-anchor") {
+                expect.anchor = true;
+            } else if arg.eq("-offset") {
+                expect.offset = true;
+            } else if arg.eq("-src") || arg.eq("-source") {
+                expect.source = true;
             } else if expect.width {
                 match arg.parse::<usize>() {
                     Ok(number) => {
@@ -150,7 +602,7 @@ fn main() {
                         expect.expect_file();
                     },
                     Err(err) => {
-                        println!("ERROR: Invalid width: {}", err.to_string());
+                        println!("ERROR: Invalid width: {}", err);
                         return;
                     }
                 }
@@ -161,7 +613,7 @@ fn main() {
                         expect.expect_file();
                     },
                     Err(err) => {
-                        println!("ERROR: Invalid width: {}", err.to_string());
+                        println!("ERROR: Invalid width: {}", err);
                         return;
                     }
                 }
@@ -178,7 +630,297 @@ fn main() {
                         }
                     },
                     Err(err) => {
-                        println!("ERROR: Invalid bits-per-pixel: {}", err.to_string());
+                        println!("ERROR: Invalid bits-per-pixel: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.quantize {
+                match parse_quantize(&arg) {
+                    Ok(quantize) => {
+                        params.quantize = quantize;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid quantization: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.dither {
+                match parse_dither(&arg) {
+                    Ok(dither) => {
+                        params.dither = dither;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid dithering: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.alpha_quantize {
+                match parse_quantize(&arg) {
+                    Ok(quantize) => {
+                        params.alpha_quantize = quantize;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid alpha quantization: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.alpha_dither {
+                match parse_dither(&arg) {
+                    Ok(dither) => {
+                        params.alpha_dither = dither;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid alpha dithering: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.background {
+                match parse_background(&arg) {
+                    Ok(background) => {
+                        params.background = background;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid background: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.extensions {
+                match parse_extensions(&arg) {
+                    Ok(extensions) => {
+                        params.extensions = extensions;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid extensions: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.animation {
+                match parse_animation(&arg) {
+                    Ok(animation) => {
+                        params.animation = animation;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid frames: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.slice {
+                match parse_slice(&arg) {
+                    Ok(slice) => {
+                        params.slice = Some(slice);
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid slice: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.scale {
+                match parse_scale(&arg) {
+                    Ok(scale) => {
+                        params.scale = scale;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid scale: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.filter {
+                match parse_filter(&arg) {
+                    Ok(filter) => {
+                        params.filter = filter;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid filter: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.anchor {
+                match parse_anchor(&arg) {
+                    Ok(anchor) => {
+                        params.anchor = anchor;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid anchor: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.offset {
+                match parse_offset(&arg) {
+                    Ok(offset) => {
+                        params.offset = offset;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid offset: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.source {
+                match parse_source(&arg) {
+                    Ok(source) => {
+                        params.source = Some(source);
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid source rectangle: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.atlas {
+                match parse_atlas(&arg) {
+                    Ok(limits) => {
+                        params.atlas = Some(limits);
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid atlas: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.strength {
+                match arg.parse::<i32>() {
+                    Ok(number) => {
+                        if (0..=200).contains(&number) {
+                            params.strength = number;
+                            expect.expect_file();
+                        } else {
+                            println!("ERROR: Invalid dithering strength (must be 0 to 200)");
+                            return;
+                        }
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid dithering strength: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.reduce {
+                match parse_reduce(&arg) {
+                    Ok(reduce) => {
+                        params.reduce = reduce;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid color reduction: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.optimize {
+                match parse_reduce(&arg) {
+                    Ok(reduce) => {
+                        global.optimize = reduce;
+                        expect.end_global_option();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid palette optimization: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.metric {
+                match parse_metric(&arg) {
+                    Ok(metric) => {
+                        global.metric = metric;
+                        expect.end_global_option();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid color metric: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.palette {
+                match load_palette(&args[a]) {
+                    Ok(palette) => {
+                        global.palette = Some(palette);
+                        expect.end_global_option();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Cannot load palette file ({}): {}", args[a], err);
+                        return;
+                    }
+                }
+            } else if expect.palette_match {
+                if arg.eq("exact") {
+                    global.nearest = false;
+                } else if arg.eq("nearest") {
+                    global.nearest = true;
+                } else {
+                    println!("ERROR: Invalid palette matching: expected exact or nearest, found '{}'", arg);
+                    return;
+                }
+                expect.end_global_option();
+            } else if expect.palette_order {
+                match parse_order(&arg) {
+                    Ok(order) => {
+                        global.order = order;
+                        expect.end_global_option();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid palette order: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.pin {
+                match parse_pins(&arg) {
+                    Ok(mut pins) => {
+                        global.pins.append(&mut pins);
+                        expect.end_global_option();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid pinned palette entry: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.reserve {
+                match parse_reserved(&arg) {
+                    Ok(mut reserved) => {
+                        global.reserved.append(&mut reserved);
+                        expect.end_global_option();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid reserved palette entry: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.incremental {
+                match load_palette(&args[a]) {
+                    Ok(palette) => {
+                        global.previous = Some(palette);
+                        expect.end_global_option();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Cannot load previous palette file ({}): {}", args[a], err);
+                        return;
+                    }
+                }
+            } else if expect.alpha_threshold {
+                match arg.parse::<u8>() {
+                    Ok(number) => {
+                        params.alpha_threshold = number;
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid alpha threshold: {}", err);
+                        return;
+                    }
+                }
+            } else if expect.chroma_key {
+                match parse_rgb888(&arg) {
+                    Ok(color) => {
+                        params.chroma_key = Some(color);
+                        expect.expect_file();
+                    },
+                    Err(err) => {
+                        println!("ERROR: Invalid chroma key: {}", err);
                         return;
                     }
                 }
@@ -199,24 +941,65 @@ fn main() {
         }
     }
 
+    if global.palette.is_some() && global.previous.is_some() {
+        println!("ERROR: A fixed palette (-p) and an incremental palette (-i) cannot be used together");
+        return;
+    }
+
+    // Unused (free or reserved) entries of a previous palette are written as black, so its
+    // black entries are free, except for the first one that is not reserved, which is kept
+    // in case black is a real color.
+    if let Some(previous) = global.previous.as_mut() {
+        let mut kept_black = false;
+        for (index, entry) in previous.iter_mut().enumerate().skip(1) {
+            if *entry == Some(Rgb::<u8>([0, 0, 0])) && !global.reserved.contains(&index) {
+                if kept_black {
+                    *entry = None;
+                }
+                kept_black = true;
+            }
+        }
+    }
+
+    // A palette index cannot be both pinned and reserved, or pinned twice, and it cannot be
+    // pinned to another color than it has in a previous palette.
+    for (position, (index, color)) in global.pins.iter().enumerate() {
+        if global.reserved.contains(index) || global.pins[..position].iter().any(|(other, _)| other == index) {
+            println!("ERROR: Palette index {} is pinned more than once, or is both pinned and reserved", index);
+            return;
+        }
+        if let Some(previous) = previous_color(&global, *index, color) {
+            println!("ERROR: Palette index {} cannot be pinned to {} {} {}, because the previous palette has {} {} {} there",
+                index, color[0], color[1], color[2], previous[0], previous[1], previous[2]);
+            return;
+        }
+    }
+
     // Make sure we have something to do.
-    if directories.len() == 0 {
+    if directories.is_empty() {
         println!("ERROR: No directories to process.");
         return;
     }
 
     // Determine the paths to all files to process.
     let mut files: Vec<FileParameters> = vec![];
+    let mut groups: Vec<(usize, DirParameters)> = vec![];
 
     for directory in &mut directories {
         // Validate certain options.
         if directory.bpp == 0 {
             directory.bpp = 8;
         }
+        if matches!(directory.scale, Scale::Stretch | Scale::Fit | Scale::Fill) &&
+                directory.width == 0 && directory.height == 0 {
+            println!("ERROR: Scaling to the output size needs -w and/or -h, for {}", directory.path);
+            return;
+        }
+        groups.push((files.len(), directory.clone()));
 
         // Skip virtual data, as there is no directory or file.
         if directory.vapor {
-            let mut params = FileParameters::new(&directory);
+            let mut params = FileParameters::new(directory);
             params.size = directory.width * directory.height * 2;
             files.push(params);
             continue;
@@ -225,44 +1008,14 @@ fn main() {
         println!("Reading: {}", directory.path);
 
         // Check for accessing a single file, rather than a directory.
-        if directory.path.to_ascii_lowercase().ends_with(".png") {
+        if has_extension(&directory.path, &INPUT_EXTENSIONS) {
             match fs::metadata(directory.path.clone()) {
                 Ok(metadata) => {
                     if metadata.is_file() {
-                        let img = image::open(directory.path.clone()).unwrap();
-                        let mut params = FileParameters::new(&directory);
-                        if directory.width == 0 {
-                            params.width = img.width() as usize;
-                        }
-                        if directory.height == 0 {
-                            params.height = img.height() as usize;
-                        }
-
-                        let mut width = params.width;
-                        match params.bpp {
-                            1 => {
-                                width = (width + 7) / 8; // 8 pixels per byte
-                            },
-                            2 => {
-                                width = (width + 3) / 4; // 4 pixels per byte
-                            },
-                            3 => {
-                                width = (width + 1) / 2; // 2 pixels per byte
-                            },
-                            4 => {
-                                width = (width + 1) / 2; // 2 pixels per byte
-                            },
-                            6 => {
-                                // 1 pixel per byte
-                            },
-                            8 => {
-                                // 1 pixel per byte
-                            },
-                            _ => {}
+                        if let Err(err) = add_input_file(&mut files, directory, &directory.path) {
+                            println!("ERROR: Cannot read file {}", err);
+                            return;
                         }
-                        params.size = width * params.height;
-
-                        files.push(params);
                     } else {
                         println!("ERROR: Specified file is not a file: {}", directory.path);
                         return;
@@ -284,53 +1037,21 @@ fn main() {
                 return;
             }
         };
-        for path in paths {
-            match path {
-                Ok(dir_entry) => {
-                    match dir_entry.file_type() {
-                        Ok(file_type) => {
-                            if file_type.is_file() {
-                                let pathname = dir_entry.path().as_os_str().to_str().unwrap().to_string();
-                                if pathname.to_ascii_lowercase().ends_with(".png") {
-                                    let img = image::open(pathname.clone()).unwrap();
-                                    let mut params = FileParameters::new(&directory);
-                                    if directory.width == 0 {
-                                        params.width = img.width() as usize;
-                                    }
-                                    if directory.height == 0 {
-                                        params.height = img.height() as usize;
-                                    }
-
-                                    let mut width = params.width;
-                                    match params.bpp {
-                                        1 => {
-                                            width = (width + 7) / 8; // 8 pixels per byte
-                                        },
-                                        2 => {
-                                            width = (width + 3) / 4; // 4 pixels per byte
-                                        },
-                                        3 => {
-                                            width = (width + 1) / 2; // 2 pixels per byte
-                                        },
-                                        4 => {
-                                            width = (width + 1) / 2; // 2 pixels per byte
-                                        },
-                                        6 => {
-                                            // 1 pixel per byte
-                                        },
-                                        8 => {
-                                            // 1 pixel per byte
-                                        },
-                                        _ => {}
-                                    }
-                                    params.size = width * params.height;
-                                    params.path = pathname;
-                                                                        
-                                    files.push(params);
-                                }
+        // Process the files in order of their names, so that the output (and the order of
+        // colors in the palette) does not depend on the order of the directory entries.
+        let mut dir_entries: Vec<fs::DirEntry> = paths.flatten().collect();
+        dir_entries.sort_by_key(|dir_entry| dir_entry.path());
+        for dir_entry in dir_entries {
+            match dir_entry.file_type() {
+                Ok(file_type) => {
+                    if file_type.is_file() {
+                        let pathname = dir_entry.path().as_os_str().to_str().unwrap().to_string();
+                        if has_extension(&pathname, &directory.extensions) {
+                            if let Err(err) = add_input_file(&mut files, directory, &pathname) {
+                                println!("ERROR: Cannot read file {}", err);
+                                return;
                             }
-                        },
-                        Err(_err) => {}
+                        }
                     }
                 },
                 Err(_err) => {}
@@ -338,12 +1059,50 @@ fn main() {
         }
     }
 
+    // Pack the images of each atlas group (all images from one directory or file) into one atlas.
+    let total = files.len();
+    let mut inserted = 0;
+    for (group, (start, directory)) in groups.iter().enumerate() {
+        if let Some(limits) = &directory.atlas {
+            let end = groups.get(group + 1).map(|(next, _)| *next).unwrap_or(total);
+            match make_atlas(&mut files, start + inserted, end + inserted, directory, limits) {
+                Ok(atlas_file) => {
+                    files.insert(end + inserted, atlas_file);
+                    inserted += 1;
+                },
+                Err(err) => {
+                    println!("ERROR: Cannot build the atlas for {}: {}", directory.path, err);
+                    return;
+                }
+            }
+        }
+    }
+
     // Make sure we have something to do.
-    if files.len() == 0 {
+    if files.is_empty() {
         println!("ERROR: No files to process.");
         return;
     }
 
+    // Input files whose names differ only in their extensions would overwrite each other's output.
+    let writes = |img_file: &FileParameters| {
+        !img_file.vapor && !img_file.no_output && !img_file.packed && !img_file.appends()
+    };
+    for (position, img_file) in files.iter().enumerate().filter(|(_, img_file)| writes(img_file)) {
+        let output = img_file.output_path();
+        if let Some(other) = files[..position].iter()
+                .find(|other| writes(other) && other.output_path() == output) {
+            let hint = if img_file.source.is_some() {
+                "give each source rectangle its own name"
+            } else {
+                "use -x to select extensions"
+            };
+            println!("ERROR: Files {} and {} would both be written as {} ({})",
+                other.name(), img_file.name(), output, hint);
+            return;
+        }
+    }
+
     // Read the contents of all files, and determine their unique pixel colors.
     for img_file in &mut files {
         // Determine the maximum number of colors, not including transparent
@@ -373,139 +1132,463 @@ fn main() {
             _ => {}
         }
 
-        // Check for needing to read the file
-        if img_file.vapor {
+        // Check for needing to read the file (an atlas has no file of its own)
+        if img_file.vapor || img_file.atlas.is_some() {
             continue; // skip it
         }
 
         // Read the file contents
-        let img = image::open(img_file.path.clone()).unwrap();
+        let img = load_image(img_file).unwrap();
         let width = img.width();
         let height = img.height();
-        println!("{}, {}x{}, {:?}", img_file.path, width, height, img.color());
-    
-        match img {
-            image::DynamicImage::ImageRgb8(rgba) => {
-                for y in 0..height {
-                    for x in 0..width {
-                        let pixel = rgba.get_pixel(x, y);
-                        let r = convert_color(pixel[IMG_R]);
-                        let g = convert_color(pixel[IMG_G]);
-                        let b = convert_color(pixel[IMG_B]);
-                        let color = Rgb::<u8>([r, g, b]);
-                        if !img_file.colors.contains_key(&color) {
-                            if img_file.colors.len() >= img_file.max_colors {
-                                println!("ERROR: File {} contains too many colors (over {})",
-                                    img_file.path, img_file.max_colors);
-                                return;
-                            }
-                            if img_file.bpp == 8 {
-                                let index = (b<<4)|(g<<2)|r;
-                                img_file.colors.insert(color, index);    
-                            } else {
-                                let index = (img_file.colors.len() + 1) as u8;
-                                img_file.colors.insert(color, index);    
-                            }
+        println!("{}, {}x{}, {:?}", img_file.name(), width, height, img.color());
+
+        // Optionally, an indexed PNG keeps its own palette order, by pinning each of
+        // its palette colors (except for index zero, which is transparent). Pixels are
+        // matched to palette entries by color, so no two entries may have the same color.
+        if img_file.file_palette && img_file.bpp != 8 {
+            match load_file_palette(img_file) {
+                Ok(Some(colors)) => {
+                    for (index, color) in colors.iter().copied().enumerate().skip(1).take(63) {
+                        if let Some(other) = colors[1..index].iter().position(|other| *other == color) {
+                            println!("ERROR: Palette indexes {} and {} of file {} have the same 6-bit color, so its palette order cannot be kept",
+                                other + 1, index, img_file.name());
+                            return;
+                        }
+                        let pinned = global.pins.iter().find(|(other, _)| *other == index);
+                        if global.reserved.contains(&index) || pinned.is_some_and(|(_, other)| *other != color) ||
+                                previous_color(&global, index, &color).is_some() {
+                            println!("ERROR: Palette index {} of file {} is reserved, or is pinned to another color, or has another color in the previous palette",
+                                index, img_file.name());
+                            return;
+                        }
+                        if pinned.is_none() {
+                            global.pins.push((index, color));
                         }
                     }
+                    println!("File {} keeps its own palette order.", img_file.name());
+                },
+                Ok(None) => {
+                    println!("File {} has no palette, so its palette order cannot be kept.", img_file.name());
+                },
+                Err(err) => {
+                    println!("ERROR: Cannot read the palette of file {}: {}", img_file.name(), err);
+                    return;
                 }
-            },
-            image::DynamicImage::ImageRgba8(rgba) => {
-                for y in 0..height {
-                    for x in 0..width {
-                        let pixel = rgba.get_pixel(x, y);
-                        //let a = convert_color(pixel[IMG_A]);
-                        /*if a > 0*/ {
-                            let r = convert_color(pixel[IMG_R]);
-                            let g = convert_color(pixel[IMG_G]);
-                            let b = convert_color(pixel[IMG_B]);
-                            let color = Rgb::<u8>([r, g, b]);
-                            if !img_file.colors.contains_key(&color) {
-                                if img_file.colors.len() >= img_file.max_colors {
-                                    println!("ERROR: File {} contains too many colors (over {})",
-                                        img_file.path, img_file.max_colors);
-                                    return;
-                                }
-                                if img_file.bpp == 8 {
-                                    let index = (b<<4)|(g<<2)|r;
-                                    img_file.colors.insert(color, index);    
-                                } else {
-                                    let index = (img_file.colors.len() + 1) as u8;
-                                    img_file.colors.insert(color, index);    
-                                }
-                            }
+            }
+        }
+
+        // Choose the background color once, so that both passes composite against it.
+        if img_file.background == Background::Auto && img_file.bpp != 8 {
+            let color = border_color(&img, img_file);
+            println!("File {} uses background color {:02X}{:02X}{:02X}.",
+                img_file.name(), color[0], color[1], color[2]);
+            img_file.background = Background::Color(color);
+        }
+
+        // Use the same converted (and possibly dithered) colors as the output pass.
+        let converted = convert_pixels(&img, img_file);
+
+        // Count the pixels of each color, keeping the order in which colors appear, and
+        // sum the 8-bit colors of the pixels that were converted to each color.
+        let mut histogram: Vec<(Rgb<u8>, usize)> = vec![];
+        let mut positions: HashMap<Rgb<u8>, usize> = HashMap::new();
+        let mut sums: HashMap<Rgb<u8>, [f64; 3]> = HashMap::new();
+        let flattened = flatten_pixels(&img, img_file);
+        for (color, pixel) in converted.iter().zip(flattened.pixels()) {
+            let Some(color) = color else {
+                continue;
+            };
+            add_pixel_color(&mut sums, color, pixel);
+            match positions.get(color) {
+                Some(position) => {
+                    histogram[*position].1 += 1;
+                },
+                None => {
+                    positions.insert(*color, histogram.len());
+                    histogram.push((*color, 1));
+                }
+            }
+        }
+
+        // Unless only the source region should contribute colors, the rest of the
+        // whole input image contributes its colors, too.
+        if let (Some(source), false) = (&img_file.source, img_file.source_palette) {
+            let whole = load_source(img_file).unwrap();
+            let whole_width = whole.width();
+            let converted = convert_pixels(&whole, img_file);
+            let flattened = flatten_pixels(&whole, img_file);
+            for (offset, (color, pixel)) in converted.iter().zip(flattened.pixels()).enumerate() {
+                let x = offset as u32 % whole_width;
+                let y = offset as u32 / whole_width;
+                let inside = x >= source.x && x < source.x + source.width &&
+                    y >= source.y && y < source.y + source.height;
+                if let (Some(color), false) = (color, inside) {
+                    add_pixel_color(&mut sums, color, pixel);
+                    match positions.get(color) {
+                        Some(position) => {
+                            histogram[*position].1 += 1;
+                        },
+                        None => {
+                            positions.insert(*color, histogram.len());
+                            histogram.push((*color, 1));
                         }
                     }
                 }
-            },
-            _ => {
-                println!("ERROR: Unhandled image format ({}). Must be RGB8 or RGBA8!", img_file.path);
+            }
+        }
+
+        if histogram.len() > img_file.max_colors {
+            if img_file.reduce != Reduce::None {
+                img_file.remap = reduce_colors(&histogram, img_file.max_colors, &img_file.reduce, false, &global.metric);
+                report_reduction(&format!("File {}", img_file.name()), &histogram, &img_file.remap, &global.metric);
+            } else if img_file.bpp == 8 ||
+                    (global.optimize == Reduce::None && !(global.palette.is_some() && global.nearest)) {
+                println!("ERROR: File {} contains too many colors (over {})",
+                    img_file.name(), img_file.max_colors);
                 return;
             }
         }
 
+        img_file.representatives = histogram.iter()
+            .map(|(color, count)| (*color, sums[color].map(|sum| sum / *count as f64)))
+            .collect();
+        img_file.histogram = histogram;
+        collect_colors(img_file);
+
         println!("File {} has {} unique colors (maximum is {}).",
-            img_file.path, img_file.colors.len(), img_file.max_colors);
+            img_file.name(), img_file.colors.len(), img_file.max_colors);
+    }
+
+    // Optionally, choose the best shared palette for all indexed files together.
+    if global.optimize != Reduce::None {
+        // Combine the color histograms of the indexed files, weighted by pixel count.
+        let mut union: Vec<(Rgb<u8>, usize)> = vec![];
+        let mut positions: HashMap<Rgb<u8>, usize> = HashMap::new();
+        for img_file in files.iter().filter(|img_file| img_file.bpp != 8) {
+            for (color, count) in &img_file.histogram {
+                let color = *img_file.remap.get(color).unwrap_or(color);
+                match positions.get(&color) {
+                    Some(position) => {
+                        union[*position].1 += count;
+                    },
+                    None => {
+                        positions.insert(color, union.len());
+                        union.push((color, *count));
+                    }
+                }
+            }
+        }
+
+        // An index is taken when it is reserved, or when it is pinned (or has a color in
+        // the previous palette) to a color that the given colors do not include.
+        let is_taken = |index: &usize, used: &HashMap<Rgb<u8>, usize>| global.reserved.contains(index) ||
+            global.pins.iter().any(|(pinned, color)| pinned == index && !used.contains_key(color)) ||
+            global.previous.as_ref().and_then(|previous| previous.get(*index).copied().flatten())
+                .is_some_and(|color| !used.contains_key(&color));
+
+        // Choose (up to) as many colors as there are free indexes, for the whole palette.
+        let room = (1..=63).filter(|index| !is_taken(index, &positions)).count().max(1);
+        let palette_remap = if union.len() > room {
+            let remap = reduce_colors(&union, room, &global.optimize, false, &global.metric);
+            report_reduction("Palette", &union, &remap, &global.metric);
+            remap
+        } else {
+            HashMap::new()
+        };
+
+        // Files with few bits-per-pixel share the low palette indexes (1, 1 to 3, 1 to 7,
+        // and 1 to 15), so all files limited to each range are reduced together, to the
+        // colors that fit into that range, smallest range first.
+        let mut range_remap: HashMap<Rgb<u8>, Rgb<u8>> = HashMap::new();
+        for limit in [1, 3, 7, 15] {
+            let mut combined: Vec<(Rgb<u8>, usize)> = vec![];
+            let mut positions: HashMap<Rgb<u8>, usize> = HashMap::new();
+            for img_file in files.iter().filter(|img_file| img_file.bpp != 8 && img_file.max_colors <= limit) {
+                for (color, count) in &img_file.histogram {
+                    let mapped = *img_file.remap.get(color).unwrap_or(color);
+                    let mapped = *palette_remap.get(&mapped).unwrap_or(&mapped);
+                    let mapped = *range_remap.get(&mapped).unwrap_or(&mapped);
+                    match positions.get(&mapped) {
+                        Some(position) => {
+                            combined[*position].1 += count;
+                        },
+                        None => {
+                            positions.insert(mapped, combined.len());
+                            combined.push((mapped, *count));
+                        }
+                    }
+                }
+            }
+
+            // Taken indexes leave less room in the range.
+            let room = (1..=limit).filter(|index| !is_taken(index, &positions)).count().max(1);
+            if combined.len() > room {
+                let subset = reduce_colors(&combined, room, &global.optimize, true, &global.metric);
+                for mapped in range_remap.values_mut() {
+                    *mapped = *subset.get(mapped).unwrap_or(mapped);
+                }
+                for (color, mapped) in subset {
+                    range_remap.entry(color).or_insert(mapped);
+                }
+            }
+        }
+
+        // Remap each file onto the shared colors, then choose the best shared
+        // colors for any file that still has more colors than its bpp allows.
+        for img_file in files.iter_mut().filter(|img_file| img_file.bpp != 8) {
+            let mut remap: HashMap<Rgb<u8>, Rgb<u8>> = HashMap::new();
+            let mut shared: Vec<(Rgb<u8>, usize)> = vec![];
+            for (color, count) in &img_file.histogram {
+                let mapped = *img_file.remap.get(color).unwrap_or(color);
+                let mapped = *palette_remap.get(&mapped).unwrap_or(&mapped);
+                let mapped = *range_remap.get(&mapped).unwrap_or(&mapped);
+                remap.insert(*color, mapped);
+                match shared.iter_mut().find(|(shared_color, _)| *shared_color == mapped) {
+                    Some(entry) => {
+                        entry.1 += count;
+                    },
+                    None => {
+                        shared.push((mapped, *count));
+                    }
+                }
+            }
+            if shared.len() > img_file.max_colors {
+                let subset = reduce_colors(&shared, img_file.max_colors, &global.optimize, true, &global.metric);
+                for mapped in remap.values_mut() {
+                    *mapped = subset[mapped];
+                }
+            }
+            img_file.remap = remap;
+            if img_file.remap.iter().any(|(color, mapped)| color != mapped) {
+                report_reduction(&format!("File {}", img_file.name()), &img_file.histogram, &img_file.remap, &global.metric);
+            }
+            collect_colors(img_file);
+        }
     }
 
     // Use the colors of all files, and consolidate their palettes.
 
     let mut palette_map: HashMap<Rgb<u8>, Vec<u8>> = HashMap::new();
     let mut palette_array: Vec<Option<Rgb::<u8>>> = vec![];
+    let mut reserved: Vec<bool> = vec![false; 64];
 
     for _index in 0..64 {
         palette_array.push(None);
     }
 
-    // Find indexes for colors.
-    let mut dump_palette = false;
-    let next_index: usize = 1;
-    for img_file in &mut files {
-        dump_palette |= img_file.bpp != 8;
-        for (color, index) in &img_file.colors {
-            if !palette_map.contains_key(&color) {
-                if dump_palette {
-                    let mut found = false;
-                    for palette_index in next_index..256 {
-                        if palette_array[palette_index].is_none() {
-                            palette_array[palette_index] = Some(color.clone());
-                            let mut indexes: Vec<u8> = vec![];
-                            indexes.push(palette_index as u8);
-                            palette_map.insert(color.clone(), indexes);            
-                            found = true;
-                            break;
+    // A fixed or previous palette occupies every index that it defines, except the
+    // transparent index (zero). Existing colors of a previous palette never move.
+    if let Some(palette) = global.palette.as_ref().or(global.previous.as_ref()) {
+        for (index, entry) in palette.iter().enumerate().skip(1) {
+            palette_array[index] = *entry;
+        }
+    }
+
+    // Pinned colors occupy their indexes, and reserved indexes are never given to image colors.
+    for (index, color) in &global.pins {
+        palette_array[*index] = Some(*color);
+    }
+    for index in &global.reserved {
+        reserved[*index] = true;
+    }
+    for (index, entry) in palette_array.iter().enumerate() {
+        if let (Some(color), false) = (entry, reserved[index]) {
+            palette_map.entry(*color).or_default().push(index as u8);
+        }
+    }
+
+    // Optionally, map the colors of all indexed files onto a fixed palette.
+    if global.palette.is_some() {
+        for img_file in files.iter_mut().filter(|img_file| img_file.bpp != 8) {
+            // A file may only use the indexes that fit into its bits-per-pixel.
+            let limit = img_file.max_colors.min(63);
+            let candidates: Vec<Rgb<u8>> = palette_array[1..=limit].iter().enumerate()
+                .filter(|(position, _)| !reserved[position + 1])
+                .filter_map(|(_, entry)| *entry)
+                .collect();
+            if candidates.is_empty() {
+                println!("ERROR: The palette has no colors in indexes 1 to {}, for file {}", limit, img_file.name());
+                return;
+            }
+            let mut remap: HashMap<Rgb<u8>, Rgb<u8>> = HashMap::new();
+            for (color, _count) in &img_file.histogram {
+                let mapped = *img_file.remap.get(color).unwrap_or(color);
+                if candidates.contains(&mapped) {
+                    remap.insert(*color, mapped);
+                } else if global.nearest {
+                    // Measure from the 8-bit colors of the pixels, rather than from their
+                    // 6-bit color, which may be nearly as far from several candidates.
+                    let original = img_file.representatives.get(color).copied()
+                        .unwrap_or_else(|| widen_components(&mapped));
+                    let distance = |candidate: &Rgb<u8>| component_distance(&original, &widen_components(candidate), &global.metric);
+                    let mut best = candidates[0];
+                    for candidate in &candidates {
+                        if distance(candidate) < distance(&best) {
+                            best = *candidate;
                         }
                     }
-                    if !found {
-                        println!("ERROR: Could not insert all colors into palette (please reduce colors)");
-                        return;
-                    }
+                    remap.insert(*color, best);
                 } else {
-                    palette_array[(*index) as usize] = Some(color.clone());
-                    let mut indexes: Vec<u8> = vec![];
-                    indexes.push(*index);
-                    palette_map.insert(color.clone(), indexes);            
+                    println!("ERROR: File {} uses color {} {} {}, which is not in palette indexes 1 to {}",
+                        img_file.name(), mapped[0], mapped[1], mapped[2], limit);
+                    return;
                 }
+            }
+            img_file.remap = remap;
+            if img_file.remap.iter().any(|(color, mapped)| color != mapped) {
+                report_reduction(&format!("File {}", img_file.name()), &img_file.histogram, &img_file.remap, &global.metric);
+            }
+            collect_colors(img_file);
+        }
+    }
+
+    // Find indexes for colors. Colors are visited in a fixed order (rather than
+    // in hash order), so that the same inputs always yield the same palette.
+    // Files with 8 bits per pixel use direct colors, so only the indexed files
+    // contribute to the palette, regardless of the order of the files.
+    let dump_palette = files.iter().any(|img_file| img_file.bpp != 8);
+    let next_index: usize = 1;
+    let mut new_colors: Vec<Rgb<u8>> = vec![];
+    for img_file in files.iter().filter(|img_file| img_file.bpp != 8) {
+        for color in ordered_colors(img_file) {
+            if !palette_map.contains_key(&color) && !new_colors.contains(&color) {
+                new_colors.push(color);
             } 
         }    
     }
 
+    // Each color needs an index that every file using it can hold in its bits-per-pixel
+    // (for example, 1 to 3 for 2 bits per pixel).
+    let mut limits: HashMap<Rgb<u8>, usize> = HashMap::new();
+    for img_file in files.iter().filter(|img_file| img_file.bpp != 8) {
+        for color in ordered_colors(img_file) {
+            let limit = limits.entry(color).or_insert(63);
+            *limit = (*limit).min(img_file.max_colors);
+        }
+    }
+
+    // A pinned color whose index is too high for some file needs another index as well.
+    for img_file in files.iter().filter(|img_file| img_file.bpp != 8) {
+        for color in ordered_colors(img_file) {
+            if let Some(indexes) = palette_map.get(&color) {
+                if indexes[0] as usize > limits[&color] && !new_colors.contains(&color) {
+                    new_colors.push(color);
+                }
+            }
+        }
+    }
+
+    // Give the low indexes to the colors of the files with the fewest bits-per-pixel first.
+    // The sort is stable, so colors with the same limit keep the requested palette order.
+    sort_palette_colors(&mut new_colors, &files, &global.order);
+    new_colors.sort_by_key(|color| limits.get(color).copied().unwrap_or(63));
+
+    if let Some(previous) = &global.previous {
+        // Each new color needs a free entry within its own index limit, so place them the
+        // same way as below (lowest limits first, each into the lowest free entry).
+        let mut free: Vec<usize> = (next_index..palette_array.len())
+            .filter(|index| palette_array[*index].is_none() && !reserved[*index])
+            .collect();
+        let free_count = free.len();
+        let mut unplaced: Vec<(Rgb<u8>, usize)> = vec![];
+        for color in &new_colors {
+            let limit = limits.get(color).copied().unwrap_or(63);
+            match free.iter().position(|index| *index <= limit) {
+                Some(position) => {
+                    free.remove(position);
+                },
+                None => {
+                    unplaced.push((*color, limit));
+                }
+            }
+        }
+        let kept = previous.iter().enumerate().skip(1)
+            .filter(|(index, entry)| entry.is_some() && !reserved[*index])
+            .count();
+        if !unplaced.is_empty() {
+            println!("ERROR: The incremental palette has {} free entries, but {} of the {} new colors do not fit:",
+                free_count, unplaced.len(), new_colors.len());
+            for (color, limit) in &unplaced {
+                let users = files.iter()
+                    .filter(|img_file| img_file.bpp != 8 && ordered_colors(img_file).contains(color))
+                    .map(|img_file| img_file.name())
+                    .collect::<Vec<String>>();
+                println!("  {} {} {}  (needs an index from 1 to {})  used by {}",
+                    color[0], color[1], color[2], limit, users.join(", "));
+            }
+            return;
+        }
+        println!("Incremental palette keeps {} existing entries, and adds {} new colors.", kept, new_colors.len());
+    }
+
+    for color in &new_colors {
+        let limit = limits.get(color).copied().unwrap_or(63);
+        let mut found = false;
+        for (palette_index, entry) in palette_array.iter_mut().enumerate().take(limit + 1).skip(next_index) {
+            if entry.is_none() && !reserved[palette_index] {
+                *entry = Some(*color);
+                let indexes = palette_map.entry(*color).or_default();
+                indexes.push(palette_index as u8);
+                indexes.sort();
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            if limit < 63 {
+                let users = files.iter()
+                    .filter(|img_file| img_file.bpp != 8 && img_file.max_colors == limit &&
+                        ordered_colors(img_file).contains(color))
+                    .map(|img_file| img_file.name())
+                    .collect::<Vec<String>>();
+                println!("ERROR: Could not fit color {} {} {} into palette indexes 1 to {}, as needed by {} (please reduce colors)",
+                    color[0], color[1], color[2], limit, users.join(", "));
+            } else {
+                println!("ERROR: Could not insert all colors into palette (please reduce colors)");
+            }
+            return;
+        }
+    }
+
     if dump_palette {
         // Dump the palette to the console, for documentation purposes.
+        println!("; Palette built from these files:");
+        for img_file in files.iter().filter(|img_file| img_file.bpp != 8 && img_file.atlas.is_none()) {
+            println!(";   {} ({} bpp)", img_file.name(), img_file.bpp);
+        }
+        if files.iter().any(|img_file| img_file.bpp == 8 && img_file.atlas.is_none()) {
+            println!(";");
+            println!("; These files use direct colors (8 bpp), not the palette:");
+            for img_file in files.iter().filter(|img_file| img_file.bpp == 8 && img_file.atlas.is_none()) {
+                println!(";   {}", img_file.name());
+            }
+        }
+        println!(";");
         println!("; Palette entries by index:");
         println!(";           Agon            Dec Hex:   R G B");
         println!(";");
         println!("begin_palette_table:");
-        for index in 0..palette_array.len() {
+        for (index, entry) in palette_array.iter().enumerate() {
             let color: Rgb<u8>;        
-            let free = match palette_array[index] {
+            let free = match entry {
                 Some(c) => {
-                    color = c.clone();
-                    ""
+                    color = *c;
+                    if global.pins.iter().any(|(pinned, _)| *pinned == index) {
+                        " (PINNED)"
+                    } else if reserved[index] {
+                        " (RESERVED)"
+                    } else {
+                        ""
+                    }
                 },
                 None => {
                     color = Rgb::<u8>([0,0,0]); // black
-                    " (FREE)"
+                    if reserved[index] {
+                        " (RESERVED)"
+                    } else {
+                        " (FREE)"
+                    }
                 }
             };
             let wcolor = widen_color(&color);
@@ -521,325 +1604,150 @@ fn main() {
     // For each PNG file, convert its pixels to palette indexes, and write to binary output file.
     // Also, write the widened RGB colors to a separate file.
     //
-    for img_file in &mut files {
+    let mut packed_images: HashMap<usize, (Vec<u8>, Vec<u8>)> = HashMap::new();
+    for (position, img_file) in files.iter_mut().enumerate() {
         if img_file.vapor || img_file.no_output {
             continue; // skip it
         }
-        println!("\n---{}---\n", img_file.path);
-        let img = image::open(img_file.path.clone()).unwrap();
-
-        // Get dimensions for input image.
-        let img_width = img.width() as i32;
-        let img_height = img.height() as i32;
-        let img_center_x = img_width / 2;
-        let img_center_y = img_height / 2;
+        println!("\n---{}---\n", img_file.name());
 
         // Get dimensions for output image.
         let out_width = img_file.width as i32;
         let out_height = img_file.height as i32;
-        let out_center_x = out_width / 2;
-        let out_center_y = out_height / 2;
-
-        // Compute necessary rectangles.
-        let out_start_x: i32;
-        let out_end_x: i32;
-        let out_start_y: i32;
-        let out_end_y: i32;
-
-        out_start_x = 0;
-        out_end_x = out_width;
-    
-        out_start_y = 0;
-        out_end_y = out_height;
-    
-        match img {
-            image::DynamicImage::ImageRgb8(rgb) => {
-                // Convert pixel colors into indexes.
-                let mut output_data: Vec<u8> = vec![];
-                let mut output_data_rgb: Vec<u8> = vec![];
-
-                for out_y in out_start_y..out_end_y {
-                    let mut bits_used: u8 = 0;
-                    let mut output_byte: u8 = 0;
-        
-                    let img_y = img_center_y - (out_center_y - out_y);
-                    if img_y < 0 || img_y >= img_height {
-                        for _out_x in out_start_x..out_end_x {
-                            // output transparent color index (zero)
-                            output_data_rgb.push(0);
-                            output_data_rgb.push(0);
-                            output_data_rgb.push(0);
-
-                            if img_file.bpp > 4 {
-                                output_data.push(0);
-                            } else {
-                                output_byte <<= img_file.bpp;
-                                bits_used += img_file.bpp;
-                                if bits_used >= 8 || 8 - bits_used < img_file.bpp {
-                                    output_data.push(output_byte);
-                                    output_byte = 0;
-                                    bits_used = 0;
-                                }    
-                            }
-                        }
-                    } else {
-                        for out_x in out_start_x..out_end_x {
-                            let img_x = img_center_x - (out_center_x - out_x);
-                            if img_x < 0 || img_x >= img_width {
-                                // output transparent color index (zero)
-                                output_data_rgb.push(0);
-                                output_data_rgb.push(0);
-                                output_data_rgb.push(0);
-
-                                if img_file.bpp > 4 {
-                                    output_data.push(0);
-                                } else {
-                                    output_byte <<= img_file.bpp;
-                                    bits_used += img_file.bpp;
-                                    if bits_used >= 8 || 8 - bits_used < img_file.bpp {
-                                        output_data.push(output_byte);
-                                        output_byte = 0;
-                                        bits_used = 0;
-                                    }    
-                                }
-                            } else {
-                                let pixel = rgb.get_pixel(img_x as u32, img_y as u32);
-                                let r = convert_color(pixel[IMG_R]);
-                                let g = convert_color(pixel[IMG_G]);
-                                let b = convert_color(pixel[IMG_B]);
-                                let color = Rgb::<u8>([r, g, b]);
-                                //if img_x==10 && img_y==10 {
-                                //    println!("{},{}: {:?} {:?}", img_x, img_y, pixel, color);
-                                //}
-
-                                let wcolor = widen_color(&color);
-                                output_data_rgb.push(wcolor[0]);
-                                output_data_rgb.push(wcolor[1]);
-                                output_data_rgb.push(wcolor[2]);
-
-                                let indexes = palette_map.get(&color).unwrap();
-                                let index = indexes[0];
-                                //print!("<{} {} {} / {} {} {} {}> ",
-                                //pixel[IMG_R],pixel[IMG_G],pixel[IMG_B],
-                                //r,g,b,index);
-
-                                // output some color index or color value
-                                if img_file.bpp > 4 {
-                                    output_data.push(index);
-                                } else {
-                                    output_byte = (output_byte << img_file.bpp) | index;
-                                    bits_used += img_file.bpp;
-                                    if bits_used >= 8 || 8 - bits_used < img_file.bpp {
-                                        output_data.push(output_byte);
-                                        output_byte = 0;
-                                        bits_used = 0;
-                                    }    
-                                }
-                            }
-                        }    
-                    }
-                    // finish the pixel row
-                    if bits_used > 0 {
-                        while bits_used < 8 {
-                            output_byte <<= img_file.bpp;
-                            bits_used += img_file.bpp;
-                        }
-                        output_data.push(output_byte);
-                    }
-                }
 
-                // Write the output data to a file.
-                let uc_path = upcase_filename(&img_file.path);
-                match fs::File::create(uc_path.clone()) {
-                    Ok(mut file) => {
-                        match file.write_all(&output_data[..]) {
-                            Ok(()) => {
-                                println!("Wrote file ({}) as {} bytes.", uc_path, output_data.len());
-                            },
-                            Err(err) => {
-                                println!("ERROR: Cannot write output file ({}): {}", uc_path, err.to_string());
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        println!("ERROR: Cannot open output file ({}): {}", uc_path, err.to_string());
-                    }
-                }
+        // Convert pixel colors into indexes (or color values), and widened RGB colors.
+        let mut indexes: Vec<u8> = vec![0; img_file.width * img_file.height];
+        let mut output_data_rgb: Vec<u8> = vec![0; img_file.width * img_file.height * 3];
 
-                // Write the output RGB data to a file.
-                let uc_path = upcase_filename(&img_file.path) + ".RGB";
-                match fs::File::create(uc_path.clone()) {
-                    Ok(mut file) => {
-                        match file.write_all(&output_data_rgb[..]) {
-                            Ok(()) => {
-                                println!("Wrote RGB file ({}) as {} bytes.", uc_path, output_data_rgb.len());
-                            },
-                            Err(err) => {
-                                println!("ERROR: Cannot write RGB output file ({}): {}", uc_path, err.to_string());
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        println!("ERROR: Cannot open RGB output file ({}): {}", uc_path, err.to_string());
+        if let Some(rects) = &img_file.atlas {
+            // Copy each packed image into its place in the atlas.
+            for rect in rects {
+                let (packed_indexes, packed_rgb) = &packed_images[&rect.position];
+                for y in 0..rect.height as usize {
+                    for x in 0..rect.width as usize {
+                        let from = y * rect.width as usize + x;
+                        let to = (rect.y as usize + y) * img_file.width + rect.x as usize + x;
+                        indexes[to] = packed_indexes[from];
+                        output_data_rgb[to * 3..to * 3 + 3].copy_from_slice(&packed_rgb[from * 3..from * 3 + 3]);
                     }
                 }
-            },
-            image::DynamicImage::ImageRgba8(rgba) => {
-                // Convert pixel colors into indexes.
-                let mut output_data: Vec<u8> = vec![];
-                let mut output_data_rgb: Vec<u8> = vec![];
-
-                for out_y in out_start_y..out_end_y {
-                    let mut bits_used: u8 = 0;
-                    let mut output_byte: u8 = 0;
-        
-                    let img_y = img_center_y - (out_center_y - out_y);
-                    if img_y < 0 || img_y >= img_height {
-                        for _out_x in out_start_x..out_end_x {
-                            // output transparent color index (zero)
-                            output_data_rgb.push(0);
-                            output_data_rgb.push(0);
-                            output_data_rgb.push(0);
-
-                            if img_file.bpp > 4 {
-                                output_data.push(0);
-                            } else {
-                                output_byte <<= img_file.bpp;
-                                bits_used += img_file.bpp;
-                                if bits_used >= 8 || 8 - bits_used < img_file.bpp {
-                                    output_data.push(output_byte);
-                                    output_byte = 0;
-                                    bits_used = 0;
-                                }    
-                            }
-                        }
-                    } else {
-                        for out_x in out_start_x..out_end_x {
-                            let img_x = img_center_x - (out_center_x - out_x);
-                            if img_x < 0 || img_x >= img_width {
-                                // output transparent color index (zero)
-                                output_data_rgb.push(0);
-                                output_data_rgb.push(0);
-                                output_data_rgb.push(0);
-
-                                if img_file.bpp > 4 {
-                                    output_data.push(0);
-                                } else {
-                                    output_byte <<= img_file.bpp;
-                                    bits_used += img_file.bpp;
-                                    if bits_used >= 8 || 8 - bits_used < img_file.bpp {
-                                        output_data.push(output_byte);
-                                        output_byte = 0;
-                                        bits_used = 0;
-                                    }    
-                                }
+            }
+            write_atlas_tables(img_file, rects);
+        } else {
+            let img = load_image(img_file).unwrap();
+            let converted = convert_pixels(&img, img_file);
+            let alphas = if img_file.bpp == 8 && img.color().has_alpha() {
+                Some(convert_alpha(&img, img_file))
+            } else {
+                None
+            };
+
+            // Get dimensions for input image, and the position of the output image over it.
+            let img_width = img.width() as i32;
+            let img_height = img.height() as i32;
+            let (origin_x, origin_y) = image_origin(img_file, img_width, img_height);
+
+            for out_y in 0..out_height {
+                let img_y = origin_y + out_y;
+                for out_x in 0..out_width {
+                    let img_x = origin_x + out_x;
+
+                    // Pixels outside of the input image, and transparent pixels, are output
+                    // as the transparent color index (zero).
+                    let mut index: u8 = 0;
+                    let mut wcolor = Rgb::<u8>([0, 0, 0]);
+                    if img_x >= 0 && img_x < img_width && img_y >= 0 && img_y < img_height {
+                        let offset = (img_y * img_width + img_x) as usize;
+                        if let Some(color) = converted[offset] {
+                            let color = *img_file.remap.get(&color).unwrap_or(&color);
+                            //if img_x==10 && img_y==10 {
+                            //    println!("{},{}: {:?}", img_x, img_y, color);
+                            //}
+
+                            wcolor = widen_color(&color);
+                            index = if img_file.bpp == 8 {
+                                img_file.colors[&color] // direct color
                             } else {
-                                let pixel = rgba.get_pixel(img_x as u32, img_y as u32);
-                                let a = convert_color(pixel[IMG_A]);
-                                /*if a > 0*/ {
-                                    let r = convert_color(pixel[IMG_R]);
-                                    let g = convert_color(pixel[IMG_G]);
-                                    let b = convert_color(pixel[IMG_B]);
-                                    let color = Rgb::<u8>([r, g, b]);
-                                    //if img_x==10 && img_y==10 {
-                                    //    println!("{},{}: {:?} {:?} {:?}", img_x, img_y, a, pixel, color);
-                                    //}
-    
-                                    let wcolor = widen_color(&color);
-                                    output_data_rgb.push(wcolor[0]);
-                                    output_data_rgb.push(wcolor[1]);
-                                    output_data_rgb.push(wcolor[2]);
-    
-                                    let indexes = palette_map.get(&color).unwrap();
-                                    let index = indexes[0];
-                                    //print!("({} {} {} / {} {} {} {}) ",
-                                    //pixel[IMG_R],pixel[IMG_G],pixel[IMG_B],
-                                    //r,g,b,index);
-    
-                                    // output some color index
-                                    if img_file.bpp == 8 {
-                                        let transparency = a << 6;
-                                        output_data.push(index|transparency);
-                                    } else if img_file.bpp > 4 {
-                                        output_data.push(index);
-                                    } else {
-                                        output_byte = (output_byte << img_file.bpp) | index;
-                                        bits_used += img_file.bpp;
-                                        if bits_used >= 8 || 8 - bits_used < img_file.bpp {
-                                            output_data.push(output_byte);
-                                            output_byte = 0;
-                                            bits_used = 0;
-                                        }    
-                                    }
-                                } /*else {
-                                    // output transparent color index (zero)
-                                    output_data_rgb.push(0);
-                                    output_data_rgb.push(0);
-                                    output_data_rgb.push(0);
-
-                                    if img_file.bpp > 4 {
-                                        output_data.push(0);
-                                    } else {
-                                        output_byte <<= img_file.bpp;
-                                        bits_used += img_file.bpp;
-                                        if bits_used >= 8 || 8 - bits_used < img_file.bpp {
-                                            output_data.push(output_byte);
-                                            output_byte = 0;
-                                            bits_used = 0;
-                                        }    
-                                    }
-                                }*/
+                                palette_map.get(&color).unwrap()[0]
+                            };
+
+                            if let Some(alphas) = &alphas {
+                                index |= alphas[offset] << 6;
                             }
-                        }    
-                    }
-                    // finish the pixel row
-                    if bits_used > 0 {
-                        while bits_used < 8 {
-                            output_byte <<= img_file.bpp;
-                            bits_used += img_file.bpp;
                         }
-                        output_data.push(output_byte);
                     }
+
+                    let pixel = (out_y * out_width + out_x) as usize;
+                    indexes[pixel] = index;
+                    output_data_rgb[pixel * 3..pixel * 3 + 3].copy_from_slice(&wcolor.0);
                 }
+            }
 
-                // Write the output data to a file.
-                let uc_path = upcase_filename(&img_file.path);
-                match fs::File::create(uc_path.clone()) {
-                    Ok(mut file) => {
-                        match file.write_all(&output_data[..]) {
-                            Ok(()) => {
-                                println!("Wrote file ({}) as {} bytes.", uc_path, output_data.len());
-                            },
-                            Err(err) => {
-                                println!("ERROR: Cannot write output file ({}): {}", uc_path, err.to_string());
-                            }
-                        }
+            // An image packed into an atlas is written as part of the atlas.
+            if img_file.packed {
+                println!("Packed into atlas.");
+                packed_images.insert(position, (indexes, output_data_rgb));
+                continue;
+            }
+        }
+
+        // output the color indexes or color values
+        let output_data = pack_pixels(&indexes, img_file.width, img_file.bpp);
+
+        // Write the output data to a file. The frames of a joined animation are appended
+        // to the output file of the first frame.
+        let appends = img_file.appends();
+        let uc_path = img_file.output_path();
+        match fs::OpenOptions::new().write(true).create(true).append(appends).truncate(!appends).open(uc_path.clone()) {
+            Ok(mut file) => {
+                match file.write_all(&output_data[..]) {
+                    Ok(()) => {
+                        println!("Wrote file ({}) as {} bytes.", uc_path, output_data.len());
                     },
                     Err(err) => {
-                        println!("ERROR: Cannot open output file ({}): {}", uc_path, err.to_string());
+                        println!("ERROR: Cannot write output file ({}): {}", uc_path, err);
                     }
                 }
+            },
+            Err(err) => {
+                println!("ERROR: Cannot open output file ({}): {}", uc_path, err);
+            }
+        }
 
-                // Write the output RGB data to a file.
-                let uc_path = upcase_filename(&img_file.path) + ".RGB";
-                match fs::File::create(uc_path.clone()) {
-                    Ok(mut file) => {
-                        match file.write_all(&output_data_rgb[..]) {
-                            Ok(()) => {
-                                println!("Wrote RGB file ({}) as {} bytes.", uc_path, output_data_rgb.len());
-                            },
-                            Err(err) => {
-                                println!("ERROR: Cannot write RGB output file ({}): {}", uc_path, err.to_string());
-                            }
-                        }
+        // Write the output RGB data to a file.
+        let uc_path = img_file.output_path() + ".RGB";
+        match fs::OpenOptions::new().write(true).create(true).append(appends).truncate(!appends).open(uc_path.clone()) {
+            Ok(mut file) => {
+                match file.write_all(&output_data_rgb[..]) {
+                    Ok(()) => {
+                        println!("Wrote RGB file ({}) as {} bytes.", uc_path, output_data_rgb.len());
                     },
                     Err(err) => {
-                        println!("ERROR: Cannot open RGB output file ({}): {}", uc_path, err.to_string());
+                        println!("ERROR: Cannot write RGB output file ({}): {}", uc_path, err);
                     }
                 }
             },
-            _ => {
-                panic!("ERROR: Unhandled image format. Must be RGBA8!");
+            Err(err) => {
+                println!("ERROR: Cannot open RGB output file ({}): {}", uc_path, err);
+            }
+        }
+    }
+
+    // Write the animation tags of each Aseprite file, whether or not its frames are used.
+    for img_file in files.iter().filter(|img_file| !img_file.tags.is_empty() && !img_file.no_output) {
+        if img_file.frame.as_ref().is_none_or(|frame| frame.index == 0) {
+            write_tag_table(img_file);
+        }
+    }
+
+    // Write a timing table for each animation, holding the delay of every frame.
+    for (position, img_file) in files.iter().enumerate() {
+        if let Some(frame) = img_file.frame.as_ref().filter(|frame| frame.index == 0 && frame.cell.is_none()) {
+            if !img_file.no_output {
+                let delays: Vec<u32> = files[position..position + frame.count].iter()
+                    .map(|frame_file| frame_file.frame.as_ref().unwrap().delay)
+                    .collect();
+                write_timing_table(img_file, &delays);
             }
         }
     }
@@ -849,8 +1757,8 @@ fn main() {
         let mut palette_bytes: Vec<u8> = vec![];
 
         // standard and custom colors
-        for index in 0..palette_array.len() {
-            match palette_array[index] {
+        for entry in &palette_array {
+            match entry {
                 Some(color) => {
                     palette_bytes.push(color[0]); // R
                     palette_bytes.push(color[1]); // G
@@ -872,77 +1780,2496 @@ fn main() {
                         println!("Wrote file ({}) as {} bytes.", uc_path, palette_bytes.len());
                     },
                     Err(err) => {
-                        println!("ERROR: Cannot write palette file ({}): {}", uc_path, err.to_string());
+                        println!("ERROR: Cannot write palette file ({}): {}", uc_path, err);
                     }
                 }
             },
             Err(err) => {
-                println!("ERROR: Cannot open palette file ({}): {}", uc_path, err.to_string());
+                println!("ERROR: Cannot open palette file ({}): {}", uc_path, err);
             }
         }
     }
 
+    if files.iter().any(|img_file| img_file.trimmed.is_some()) {
+        write_metadata(&files);
+    }
+
     show_memory_map(&mut files);
 }
 
-fn convert_color(color: u8) -> u8 {
-    color >> 6
+fn convert_color(color: u8, quantize: &Quantize) -> u8 {
+    match quantize {
+        Quantize::Truncate => {
+            color >> 6
+        },
+        Quantize::Nearest => {
+            // Choose the level whose widened value is closest to the input.
+            let mut level: u8 = 0;
+            for candidate in 1..4 {
+                let distance = (widen_component(candidate) as i32 - color as i32).abs();
+                let best = (widen_component(level) as i32 - color as i32).abs();
+                if distance < best {
+                    level = candidate;
+                }
+            }
+            level
+        },
+        Quantize::Thresholds(thresholds) => {
+            thresholds.iter().filter(|threshold| color >= **threshold).count() as u8
+        }
+    }
 }
 
-fn upcase_filename(path: &str) -> String {
-    let parts = path.split("/").collect::<Vec<&str>>();
-    let mut output_path = String::new();
-    for i in 0..parts.len()-1 {
-        output_path.push_str(parts[i]);
-        output_path.push_str("/");
+fn parse_quantize(text: &str) -> Result<Quantize, String> {
+    match text {
+        "t" | "truncate" => Ok(Quantize::Truncate),
+        "r" | "round" | "nearest" => Ok(Quantize::Nearest),
+        _ => {
+            // Expect a table of 3 thresholds, such as "43,128,213".
+            let parts = text.split(',').collect::<Vec<&str>>();
+            if parts.len() != 3 {
+                return Err(format!("expected truncate, nearest, or 3 thresholds, found '{}'", text));
+            }
+            let mut thresholds = [0u8; 3];
+            for (threshold, part) in thresholds.iter_mut().zip(parts) {
+                *threshold = part.trim().parse::<u8>().map_err(|err| err.to_string())?;
+            }
+            if thresholds[0] == 0 || thresholds[0] > thresholds[1] || thresholds[1] > thresholds[2] {
+                return Err("thresholds must be non-zero and ascending".to_string());
+            }
+            Ok(Quantize::Thresholds(thresholds))
+        }
     }
+}
 
-    let parts2 = parts[parts.len()-1].split(".").collect::<Vec<&str>>();
-    for i in 0..parts2.len()-1 {
-        output_path.push_str(&parts2[i].to_ascii_uppercase());
-        output_path.push_str(".");
+fn parse_dither(text: &str) -> Result<Dither, String> {
+    match text {
+        "none" => Ok(Dither::None),
+        "fs" | "floyd" | "floyd-steinberg" => Ok(Dither::FloydSteinberg),
+        "atkinson" => Ok(Dither::Atkinson),
+        "sierra" | "sierra-lite" => Ok(Dither::SierraLite),
+        "bayer2" => Ok(Dither::Ordered(2)),
+        "bayer4" => Ok(Dither::Ordered(4)),
+        "bayer8" => Ok(Dither::Ordered(8)),
+        _ => Err(format!("expected none, fs, atkinson, sierra, bayer2, bayer4, or bayer8, found '{}'", text))
     }
-    output_path.push_str("BIN");
+}
 
-    output_path
+// Returns the threshold rank (0..size*size) of a Bayer matrix cell.
+fn bayer_value(size: i32, x: i32, y: i32) -> i32 {
+    if size <= 1 {
+        return 0;
+    }
+    const BASE: [[i32; 2]; 2] = [[0, 2], [3, 1]];
+    let half = size / 2;
+    4 * bayer_value(half, x % half, y % half) + BASE[(y / half) as usize][(x / half) as usize]
 }
 
-fn show_memory_map(files: &mut Vec<FileParameters>) {
-    println!("\nRelative Memory Map\n");
-    println!("Start  End    Size   Width Height Path/Name");
-    println!("------ ------ ------ ----- ------ ----------------------------------");
+// Returns the ordered dithering offset to add to a color (or alpha) component of a pixel,
+// given the quantization in use, and the pixel's position in the output image.
+fn ordered_offset(img_file: &FileParameters, quantize: &Quantize, size: i32, out_x: i32, out_y: i32) -> i32 {
+    let cells = size * size;
+    let rank = bayer_value(size, out_x.rem_euclid(size), out_y.rem_euclid(size));
+    let offset = match quantize {
+        // Truncation rounds down, so push values up by up to one 64-value step.
+        Quantize::Truncate => (rank * 2 + 1) * 64 / (cells * 2),
+        // Rounding picks the nearest level, so spread values around one 85-value step.
+        _ => (rank * 2 + 1 - cells) * 85 / (cells * 2)
+    };
+    offset * img_file.strength / 100
+}
 
-    let mut address: usize = 0;
-    loop {
-        if files.len() == 0 {
-            break; // no more files to arrange
+// Returns whether a file name ends with one of the given extensions (ignoring case).
+fn has_extension<S: AsRef<str>>(path: &str, extensions: &[S]) -> bool {
+    let lc_path = path.to_ascii_lowercase();
+    extensions.iter().any(|extension| lc_path.ends_with(&format!(".{}", extension.as_ref())))
+}
+
+fn parse_extensions(text: &str) -> Result<Vec<String>, String> {
+    let mut extensions: Vec<String> = vec![];
+    for extension in text.split(',') {
+        let extension = extension.trim().trim_start_matches('.');
+        if !INPUT_EXTENSIONS.contains(&extension) {
+            return Err(format!("expected some of {}, found '{}'", INPUT_EXTENSIONS.join(", "), extension));
         }
+        extensions.push(extension.to_string());
+    }
+    Ok(extensions)
+}
 
-        let file = files[0].clone();
+// Adds an input file to the list of files to process. When the frames of an animated
+// file are wanted, or when the file is a sheet to be sliced, each frame or cell is added
+// as a file of its own. On failure, the error names the file.
+// The decoded image of each frame, and the decoded sheet of the cells, are kept with the
+// files, so that an animation or sheet is only decoded once.
+fn add_input_file(files: &mut Vec<FileParameters>, directory: &DirParameters, path: &str) -> Result<(), String> {
+    let mut params = FileParameters::new(directory);
+    params.path = path.to_string();
+    let error = |err: String| format!("{}: {}", path, err);
+    let animated = directory.slice.is_none() && params.animation != Animation::First;
 
-        if file.no_output {
-            files.remove(0);
-            continue; // skip it
+    // An Aseprite file is decoded once, for its frames, its animation tags, and its palette.
+    let frames = if has_extension(path, &ASEPRITE_EXTENSIONS) {
+        let aseprite = load_aseprite(path).map_err(error)?;
+        params.tags = aseprite.tags;
+        params.aseprite_palette = aseprite.palette;
+        aseprite.frames.into_iter()
+            .map(|(frame, delay)| (image::DynamicImage::ImageRgba8(frame), delay))
+            .collect()
+    } else if animated {
+        open_frames(path).map_err(error)?
+    } else {
+        vec![]
+    };
+
+    if animated {
+        let frame_count = frames.len();
+        if frame_count > 1 {
+            for (index, (frame_img, delay)) in frames.into_iter().enumerate() {
+                let mut frame_params = params.clone();
+                frame_params.frame = Some(FrameInfo { index, count: frame_count, delay, cell: None });
+                let region = source_region(&frame_img, &frame_params).map_err(error)?;
+                frame_params.image = Some(Rc::new(frame_img));
+                push_file(files, directory, frame_params, &region);
+            }
+            return Ok(());
         }
+    }
 
-        let last_address = address + file.size - 1;
-        println!("{:05x}H {:05x}H {:6} {:5} {:5}  {}",
-            address,
-            last_address,
-            file.size,
-            file.width,
-            file.height,
-            file.path);
+    let whole = match frames.into_iter().next() {
+        Some((frame_img, _)) => frame_img,
+        None => open_image(path).map_err(error)?
+    };
+    let img = source_region(&whole, &params).map_err(error)?;
+    if let Some(slice) = &directory.slice {
+        let cells = slice_cells(&img, &params);
+        if cells.is_empty() {
+            return Err(format!("{}: no cells (that are not fully transparent) were found", path));
+        }
+        params.image = Some(Rc::new(whole));
+        for (index, cell) in cells.iter().enumerate() {
+            let mut cell_params = params.clone();
+            cell_params.frame = Some(FrameInfo { index, count: cells.len(), delay: 0, cell: Some(*cell) });
+            let cell_img = img.crop_imm(cell.0, cell.1, slice.width, slice.height);
+            push_file(files, directory, cell_params, &cell_img);
+        }
+        return Ok(());
+    }
+    push_file(files, directory, params, &img);
+    Ok(())
+}
 
-        files.remove(0);
-        address += file.size;
+// Adds one image to process (a whole file, one frame, or one cell), trimming
+// its transparent borders when requested, and setting its output size.
+fn push_file(files: &mut Vec<FileParameters>, directory: &DirParameters, mut params: FileParameters, img: &image::DynamicImage) {
+    params.scaled = scaled_size(img.width(), img.height(), &params);
+    let scaled_img;
+    let img = match params.scaled {
+        Some((width, height)) => {
+            scaled_img = scale_image(img, width, height, &params.filter);
+            &scaled_img
+        },
+        None => img
+    };
+    let mut width = img.width();
+    let mut height = img.height();
+    if params.trim {
+        params.trimmed = trim_box(img, &params);
+        if let Some(trim) = &params.trimmed {
+            width = trim.width;
+            height = trim.height;
+        }
     }
+    set_output_size(&mut params, directory, width, height);
+    files.push(params);
 }
 
-fn widen_color(color: &Rgb<u8>) -> Rgb<u8> {
-    Rgb([widen_component(color[0]), widen_component(color[1]), widen_component(color[2])])
+// Sets the output image size of a file (unless it was given), and the size of its binary
+// output data, given the size of its input image.
+fn set_output_size(params: &mut FileParameters, directory: &DirParameters, width: u32, height: u32) {
+    if directory.width == 0 {
+        params.width = width as usize;
+    }
+    if directory.height == 0 {
+        params.height = height as usize;
+    }
+
+    params.size = row_size(params.width, params.bpp) * params.height;
 }
 
-fn widen_component(component: u8) -> u8 {
-    component << 6 | component << 4 | component << 2 | component
-}
\ No newline at end of file
+// Returns the number of bytes in one row of pixels, for the given bits-per-pixel.
+fn row_size(width: usize, bpp: u8) -> usize {
+    let mut width = width;
+    match bpp {
+        1 => {
+            width = width.div_ceil(8); // 8 pixels per byte
+        },
+        2 => {
+            width = width.div_ceil(4); // 4 pixels per byte
+        },
+        3 => {
+            width = width.div_ceil(2); // 2 pixels per byte
+        },
+        4 => {
+            width = width.div_ceil(2); // 2 pixels per byte
+        },
+        6 => {
+            // 1 pixel per byte
+        },
+        8 => {
+            // 1 pixel per byte
+        },
+        _ => {}
+    }
+    width
+}
+
+// Marks the images in files[start..end] as packed into an atlas, packs them, and returns
+// the atlas, to be processed (and written) after them.
+fn make_atlas(files: &mut [FileParameters], start: usize, end: usize,
+        directory: &DirParameters, limits: &AtlasLimits) -> Result<FileParameters, String> {
+    let members = &mut files[start..end];
+    if members.is_empty() {
+        return Err("there are no images to pack".to_string());
+    }
+    let sizes: Vec<(u32, u32)> = members.iter().map(|member| (member.width as u32, member.height as u32)).collect();
+    let (positions, width, height) = pack_atlas(&sizes, limits)?;
+
+    let mut atlas = FileParameters::new(directory);
+    atlas.path = if has_extension(&directory.path, &INPUT_EXTENSIONS) {
+        upcase_filename(&directory.path)
+    } else {
+        format!("{}/ATLAS.BIN", directory.path.trim_end_matches('/'))
+    };
+    let prefix = atlas.path.rsplit('/').next().unwrap_or("").trim_end_matches(".BIN").to_string();
+
+    let mut rects: Vec<AtlasRect> = vec![];
+    for (index, (member, (x, y))) in members.iter_mut().zip(positions).enumerate() {
+        member.packed = true;
+        let base_path = member.base_path();
+        let stem = base_path.rsplit('/').next().unwrap_or("").trim_end_matches(".BIN");
+        let label = match &member.frame {
+            Some(frame) => format!("{}_{}_{}", prefix, stem, frame.index),
+            None => format!("{}_{}", prefix, stem)
+        };
+        rects.push(AtlasRect {
+            position: start + index,
+            name: member.name(),
+            label: label.to_ascii_uppercase().chars()
+                .map(|letter| if letter.is_ascii_alphanumeric() { letter } else { '_' })
+                .collect(),
+            x,
+            y,
+            width: member.width as u32,
+            height: member.height as u32,
+            trim: trim_offset(member).map(|(x, y, trim)| (x, y, trim.original_width, trim.original_height))
+        });
+    }
+
+    atlas.width = width as usize;
+    atlas.height = height as usize;
+    atlas.size = row_size(atlas.width, atlas.bpp) * atlas.height;
+    atlas.atlas = Some(rects);
+    Ok(atlas)
+}
+
+// Packs rectangles of the given sizes into an atlas, using shelf packing: the rectangles
+// are taken from tallest to shortest, and each one is placed on the first shelf (row of
+// rectangles) with room left for it, or else on a new shelf. Returns the position of each
+// rectangle, and the size of the atlas.
+type Packing = (Vec<(u32, u32)>, u32, u32);
+fn pack_atlas(sizes: &[(u32, u32)], limits: &AtlasLimits) -> Result<Packing, String> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|index| std::cmp::Reverse((sizes[*index].1, sizes[*index].0)));
+
+    let mut shelves: Vec<(u32, u32, u32)> = vec![]; // top, height, and next free left position
+    let mut positions: Vec<(u32, u32)> = vec![(0, 0); sizes.len()];
+    let mut width: u32 = 0;
+    let mut height: u32 = 0;
+    for index in order {
+        let (rect_width, rect_height) = sizes[index];
+        if rect_width > limits.width || rect_height > limits.height {
+            return Err(format!("an image of {}x{} is larger than the atlas", rect_width, rect_height));
+        }
+        let shelf = shelves.iter_mut()
+            .find(|(_, shelf_height, left)| rect_height <= *shelf_height && left + rect_width <= limits.width);
+        let position = match shelf {
+            Some((top, _, left)) => {
+                let position = (*left, *top);
+                *left += rect_width + limits.padding;
+                position
+            },
+            None => {
+                let top = shelves.last().map(|(top, shelf_height, _)| top + shelf_height + limits.padding).unwrap_or(0);
+                if top + rect_height > limits.height {
+                    return Err(format!("the images do not fit into {}x{}", limits.width, limits.height));
+                }
+                shelves.push((top, rect_height, rect_width + limits.padding));
+                (0, top)
+            }
+        };
+        positions[index] = position;
+        width = width.max(position.0 + rect_width);
+        height = height.max(position.1 + rect_height);
+    }
+    Ok((positions, width, height))
+}
+
+// Writes the rectangle table of an atlas, as assembler source text (also printed to the
+// console), and as JSON, next to the atlas output file.
+fn write_atlas_tables(atlas: &FileParameters, rects: &[AtlasRect]) {
+    let stem = atlas.path.trim_end_matches(".BIN");
+
+    let mut asm = format!("; Atlas rectangles for {} (x, y, width, height):\n", atlas.path);
+    let mut entries: Vec<String> = vec![];
+    for rect in rects {
+        asm.push_str(&format!("{}:    DW    {}, {}, {}, {}  ; {}\n",
+            rect.label, rect.x, rect.y, rect.width, rect.height, rect.name));
+        let mut fields = vec![
+            format!("\"name\": {}", json_string(&rect.name)),
+            format!("\"label\": {}", json_string(&rect.label)),
+            format!("\"x\": {}", rect.x),
+            format!("\"y\": {}", rect.y),
+            format!("\"width\": {}", rect.width),
+            format!("\"height\": {}", rect.height)
+        ];
+        if let Some((x, y, original_width, original_height)) = rect.trim {
+            fields.push(format!("\"offset_x\": {}", x));
+            fields.push(format!("\"offset_y\": {}", y));
+            fields.push(format!("\"original_width\": {}", original_width));
+            fields.push(format!("\"original_height\": {}", original_height));
+        }
+        entries.push(format!("    {{ {} }}", fields.join(", ")));
+    }
+    let json = format!("[\n{}\n]\n", entries.join(",\n"));
+    println!("{}", asm);
+
+    for (path, text) in [(format!("{}.ASM", stem), asm), (format!("{}.JSON", stem), json)] {
+        match fs::File::create(path.clone()) {
+            Ok(mut file) => {
+                match file.write_all(text.as_bytes()) {
+                    Ok(()) => {
+                        println!("Wrote file ({}) as {} bytes.", path, text.len());
+                    },
+                    Err(err) => {
+                        println!("ERROR: Cannot write atlas table file ({}): {}", path, err);
+                    }
+                }
+            },
+            Err(err) => {
+                println!("ERROR: Cannot open atlas table file ({}): {}", path, err);
+            }
+        }
+    }
+}
+
+// Returns the positions of the cells of a sliced sheet, in row order, leaving out
+// cells that are fully transparent (or transparent because of '-a' or '-k').
+fn slice_cells(img: &image::DynamicImage, img_file: &FileParameters) -> Vec<(u32, u32)> {
+    let slice = img_file.slice.as_ref().unwrap();
+    let rgba = img.to_rgba8();
+    let mut cells: Vec<(u32, u32)> = vec![];
+    let mut y = slice.margin;
+    while y + slice.height <= rgba.height().saturating_sub(slice.margin) {
+        let mut x = slice.margin;
+        while x + slice.width <= rgba.width().saturating_sub(slice.margin) {
+            let visible = (y..y + slice.height).any(|pixel_y| (x..x + slice.width).any(|pixel_x| {
+                let pixel = rgba.get_pixel(pixel_x, pixel_y);
+                pixel[IMG_A] > 0 && !is_transparent(pixel, img_file)
+            }));
+            if visible {
+                cells.push((x, y));
+            }
+            x += slice.width + slice.spacing;
+        }
+        y += slice.height + slice.spacing;
+    }
+    cells
+}
+
+// Reads every frame of an animated GIF, PNG (APNG), or Aseprite file, fully composited,
+// along with the delay of each frame, in milliseconds. Returns no frames for other files.
+fn open_frames(path: &str) -> Result<Vec<(image::DynamicImage, u32)>, String> {
+    use image::AnimationDecoder;
+    if has_extension(path, &ASEPRITE_EXTENSIONS) {
+        let aseprite = load_aseprite(path)?;
+        return Ok(aseprite.frames.into_iter()
+            .map(|(frame, delay)| (image::DynamicImage::ImageRgba8(frame), delay))
+            .collect());
+    }
+    let reader = std::io::BufReader::new(fs::File::open(path).map_err(|err| err.to_string())?);
+    let frames = if has_extension(path, &["gif"]) {
+        let decoder = image::codecs::gif::GifDecoder::new(reader).map_err(|err| err.to_string())?;
+        decoder.into_frames().collect_frames().map_err(|err| err.to_string())?
+    } else if has_extension(path, &["png"]) {
+        let decoder = image::codecs::png::PngDecoder::new(reader).map_err(|err| err.to_string())?;
+        if !decoder.is_apng() {
+            return Ok(vec![]);
+        }
+        decoder.apng().into_frames().collect_frames().map_err(|err| err.to_string())?
+    } else {
+        return Ok(vec![]);
+    };
+    Ok(frames.into_iter().map(|frame| {
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        (image::DynamicImage::ImageRgba8(frame.into_buffer()), numerator / denominator.max(1))
+    }).collect())
+}
+
+// The contents of an Aseprite file: the composited frames (each with its delay in
+// milliseconds), the animation tags (name, first frame, last frame), and the 8-bit
+// palette of an indexed file.
+struct Aseprite {
+    pub frames: Vec<(image::RgbaImage, u32)>,
+    pub tags: Vec<(String, usize, usize)>,
+    pub palette: Option<Vec<[u8; 4]>>
+}
+
+// A layer of an Aseprite file.
+struct AseLayer {
+    pub visible: bool,      // the layer, and every group that holds it, is visible
+    pub opacity: u8,
+    pub background: bool,   // transparent palette entries are drawn as opaque
+    pub tilemap: bool
+}
+
+// The image of one layer in one frame of an Aseprite file, in the color depth of the file.
+#[derive(Clone)]
+struct AseCel {
+    pub x: i32,
+    pub y: i32,
+    pub opacity: u8,
+    pub z_index: i32,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>
+}
+
+// Reads the little-endian values of an Aseprite file.
+struct AseReader<'a> {
+    pub data: &'a [u8],
+    pub position: usize
+}
+
+impl<'a> AseReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.position + count > self.data.len() {
+            return Err("the file is truncated".to_string());
+        }
+        self.position += count;
+        Ok(&self.data[self.position - count..self.position])
+    }
+
+    pub fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn word(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn short(&mut self) -> Result<i16, String> {
+        Ok(self.word()? as i16)
+    }
+
+    pub fn dword(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let length = self.word()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(length)?).to_string())
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.position.min(self.data.len())..];
+        self.position = self.data.len();
+        rest
+    }
+}
+
+fn load_aseprite(path: &str) -> Result<Aseprite, String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    decode_aseprite(&data)
+}
+
+// Decodes an Aseprite file (RGBA, grayscale, or indexed), flattening the visible layers
+// of each frame (using normal blending), and reading its animation tags and palette.
+fn decode_aseprite(data: &[u8]) -> Result<Aseprite, String> {
+    let mut header = AseReader::new(data);
+    header.bytes(4)?; // file size
+    if header.word()? != 0xA5E0 {
+        return Err("not an Aseprite file".to_string());
+    }
+    let frame_count = header.word()? as usize;
+    let width = header.word()? as u32;
+    let height = header.word()? as u32;
+    let depth = header.word()?;
+    let flags = header.dword()?;
+    header.bytes(10)?; // speed, and reserved values
+    let transparent = header.byte()?;
+    if ![8, 16, 32].contains(&depth) {
+        return Err(format!("unsupported color depth ({})", depth));
+    }
+    let pixel_size = depth as usize / 8;
+
+    let mut layers: Vec<AseLayer> = vec![];
+    let mut groups: Vec<bool> = vec![]; // visibility of the current group at each level
+    let mut tags: Vec<(String, usize, usize)> = vec![];
+    let mut palette: Vec<[u8; 4]> = vec![];
+    let mut new_palette = false;
+    let mut cels: Vec<Vec<(usize, AseCel)>> = vec![];
+    let mut delays: Vec<u32> = vec![];
+
+    let mut reader = AseReader::new(data);
+    reader.position = 128;
+    for _frame in 0..frame_count {
+        let frame_start = reader.position;
+        let frame_size = reader.dword()? as usize;
+        if reader.word()? != 0xF1FA {
+            return Err("invalid frame header".to_string());
+        }
+        let old_chunk_count = reader.word()? as usize;
+        delays.push(reader.word()? as u32);
+        reader.bytes(2)?;
+        let chunk_count = match reader.dword()? as usize {
+            0 => old_chunk_count,
+            count => count
+        };
+
+        let mut frame_cels: Vec<(usize, AseCel)> = vec![];
+        for _chunk in 0..chunk_count {
+            let chunk_size = reader.dword()? as usize;
+            let chunk_type = reader.word()?;
+            if chunk_size < 6 {
+                return Err("invalid chunk size".to_string());
+            }
+            let mut chunk = AseReader::new(reader.bytes(chunk_size - 6)?);
+            match chunk_type {
+                0x0004 if !new_palette => {
+                    // old palette chunk
+                    let mut index: usize = 0;
+                    for _packet in 0..chunk.word()? {
+                        index += chunk.byte()? as usize;
+                        let count = match chunk.byte()? {
+                            0 => 256,
+                            count => count as usize
+                        };
+                        if index + count > 256 {
+                            return Err("invalid palette chunk".to_string());
+                        }
+                        for _entry in 0..count {
+                            let rgb = chunk.bytes(3)?;
+                            if palette.len() <= index {
+                                palette.resize(index + 1, [0, 0, 0, 255]);
+                            }
+                            palette[index] = [rgb[0], rgb[1], rgb[2], 255];
+                            index += 1;
+                        }
+                    }
+                },
+                0x2004 => {
+                    // layer chunk; a layer is visible only when every group that holds it is visible
+                    let layer_flags = chunk.word()?;
+                    let layer_type = chunk.word()?;
+                    let level = chunk.word()? as usize;
+                    chunk.bytes(6)?; // default size, and blend mode
+                    let opacity = chunk.byte()?;
+                    groups.truncate(level);
+                    let visible = layer_flags & 1 != 0 && layer_flags & 64 == 0 && groups.iter().all(|group| *group);
+                    groups.push(visible);
+                    layers.push(AseLayer {
+                        visible,
+                        opacity: if flags & 1 != 0 { opacity } else { 255 },
+                        background: layer_flags & 8 != 0,
+                        tilemap: layer_type == 2
+                    });
+                },
+                0x2005 => {
+                    // cel chunk
+                    let layer = chunk.word()? as usize;
+                    let x = chunk.short()? as i32;
+                    let y = chunk.short()? as i32;
+                    let opacity = chunk.byte()?;
+                    let cel_type = chunk.word()?;
+                    let z_index = chunk.short()? as i32;
+                    chunk.bytes(5)?;
+                    match cel_type {
+                        0 | 2 => {
+                            let cel_width = chunk.word()? as usize;
+                            let cel_height = chunk.word()? as usize;
+                            let pixels = if cel_type == 2 {
+                                miniz_oxide::inflate::decompress_to_vec_zlib(chunk.rest())
+                                    .map_err(|_| "cannot decompress a cel".to_string())?
+                            } else {
+                                chunk.rest().to_vec()
+                            };
+                            if pixels.len() < cel_width * cel_height * pixel_size {
+                                return Err("a cel is truncated".to_string());
+                            }
+                            frame_cels.push((layer, AseCel {
+                                x, y, opacity, z_index, width: cel_width, height: cel_height, pixels
+                            }));
+                        },
+                        1 => {
+                            // linked cel, which shares the cel of an earlier frame
+                            let linked_frame = chunk.word()? as usize;
+                            if let Some((_, linked)) = cels.get(linked_frame)
+                                    .and_then(|linked_cels| linked_cels.iter().find(|(other, _)| *other == layer)) {
+                                frame_cels.push((layer, linked.clone()));
+                            }
+                        },
+                        _ => {
+                            if layers.get(layer).is_some_and(|layer| layer.visible) {
+                                return Err("visible tilemap layers are not supported".to_string());
+                            }
+                        }
+                    }
+                },
+                0x2018 => {
+                    // tags chunk
+                    let count = chunk.word()?;
+                    chunk.bytes(8)?;
+                    for _tag in 0..count {
+                        let first = chunk.word()? as usize;
+                        let last = chunk.word()? as usize;
+                        chunk.bytes(13)?; // direction, repeat count, and color
+                        tags.push((chunk.string()?, first, last));
+                    }
+                },
+                0x2019 => {
+                    // palette chunk
+                    let size = chunk.dword()? as usize;
+                    let first = chunk.dword()? as usize;
+                    let last = chunk.dword()? as usize;
+                    chunk.bytes(8)?;
+                    if size > 256 || first > last || last >= size {
+                        return Err("invalid palette chunk".to_string());
+                    }
+                    palette.resize(size, [0, 0, 0, 255]);
+                    for entry in &mut palette[first..=last] {
+                        let entry_flags = chunk.word()?;
+                        let rgba = chunk.bytes(4)?;
+                        *entry = [rgba[0], rgba[1], rgba[2], rgba[3]];
+                        if entry_flags & 1 != 0 {
+                            chunk.string()?; // entry name
+                        }
+                    }
+                    new_palette = true;
+                },
+                _ => {}
+            }
+        }
+        cels.push(frame_cels);
+        reader.position = frame_start + frame_size;
+    }
+
+    // Flatten the visible layers of each frame, in layer order (adjusted by z-index).
+    let mut frames: Vec<(image::RgbaImage, u32)> = vec![];
+    for (frame_cels, delay) in cels.iter_mut().zip(delays) {
+        frame_cels.sort_by_key(|(layer, cel)| (*layer as i32 + cel.z_index, cel.z_index));
+        let mut canvas = image::RgbaImage::new(width, height);
+        for (layer_index, cel) in frame_cels.iter() {
+            let layer = match layers.get(*layer_index) {
+                Some(layer) if layer.visible && !layer.tilemap => layer,
+                _ => continue
+            };
+            let opacity = layer.opacity as u32 * cel.opacity as u32 / 255;
+            for cel_y in 0..cel.height {
+                for cel_x in 0..cel.width {
+                    let x = cel.x + cel_x as i32;
+                    let y = cel.y + cel_y as i32;
+                    if x < 0 || x >= width as i32 || y < 0 || y >= height as i32 {
+                        continue;
+                    }
+                    let offset = (cel_y * cel.width + cel_x) * pixel_size;
+                    let pixel = &cel.pixels[offset..offset + pixel_size];
+                    let source = match depth {
+                        32 => [pixel[0], pixel[1], pixel[2], pixel[3]],
+                        16 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+                        _ => {
+                            if pixel[0] == transparent && !layer.background {
+                                [0, 0, 0, 0]
+                            } else {
+                                palette.get(pixel[0] as usize).copied().unwrap_or([0, 0, 0, 255])
+                            }
+                        }
+                    };
+                    blend_pixel(canvas.get_pixel_mut(x as u32, y as u32), source, opacity);
+                }
+            }
+        }
+        frames.push((canvas, delay));
+    }
+
+    Ok(Aseprite {
+        frames,
+        tags,
+        palette: if depth == 8 { Some(palette) } else { None }
+    })
+}
+
+// Draws a pixel over another pixel (normal blending), with the given extra opacity.
+fn blend_pixel(pixel: &mut Rgba<u8>, source: [u8; 4], opacity: u32) {
+    let alpha = source[3] as u32 * opacity / 255;
+    if alpha == 0 {
+        return;
+    }
+    let below = pixel[IMG_A] as u32 * (255 - alpha) / 255;
+    let total = alpha + below;
+    for (component, channel) in [IMG_R, IMG_G, IMG_B].into_iter().enumerate() {
+        pixel[channel] = ((source[component] as u32 * alpha + pixel[channel] as u32 * below) / total) as u8;
+    }
+    pixel[IMG_A] = total as u8;
+}
+
+// Reads the image of a file to process, the image of one frame of an animated file,
+// or the image of one cell of a sliced sheet, and trims it when requested.
+fn load_image(img_file: &FileParameters) -> Result<image::DynamicImage, String> {
+    let whole = load_source(img_file)?;
+    let (mut x, mut y, mut width, mut height) = source_rect(&whole, img_file)?;
+    if let Some(FrameInfo { cell: Some((cell_x, cell_y)), .. }) = &img_file.frame {
+        let slice = img_file.slice.as_ref().unwrap();
+        (x, y, width, height) = (x + cell_x, y + cell_y, slice.width, slice.height);
+    }
+    let img = whole.crop_imm(x, y, width, height);
+    let img = match img_file.scaled {
+        Some((width, height)) => scale_image(&img, width, height, &img_file.filter),
+        None => img
+    };
+    Ok(match &img_file.trimmed {
+        Some(trim) => img.crop_imm(trim.x, trim.y, trim.width, trim.height),
+        None => img
+    })
+}
+
+// Returns the whole input image of a file (or the whole frame, for a frame of an animation),
+// before any of its source region, cell, scaling, or trimming are applied. Frames and cells
+// keep their decoded image; other files are read again.
+fn load_source(img_file: &FileParameters) -> Result<Rc<image::DynamicImage>, String> {
+    match &img_file.image {
+        Some(img) => Ok(img.clone()),
+        None => Ok(Rc::new(open_image(&img_file.path)?))
+    }
+}
+
+// Returns the source region of an image (see '-src'), or the whole image when no
+// region was given.
+fn source_region(img: &image::DynamicImage, img_file: &FileParameters) -> Result<image::DynamicImage, String> {
+    let (x, y, width, height) = source_rect(img, img_file)?;
+    Ok(img.crop_imm(x, y, width, height))
+}
+
+// Returns the source rectangle (x, y, width, height) of an image, which is the whole
+// image when no region was given.
+fn source_rect(img: &image::DynamicImage, img_file: &FileParameters) -> Result<(u32, u32, u32, u32), String> {
+    match &img_file.source {
+        Some(source) => {
+            if source.x + source.width > img.width() || source.y + source.height > img.height() {
+                return Err(format!("the source rectangle {},{},{},{} does not fit inside the {}x{} image",
+                    source.x, source.y, source.width, source.height, img.width(), img.height()));
+            }
+            Ok((source.x, source.y, source.width, source.height))
+        },
+        None => Ok((0, 0, img.width(), img.height()))
+    }
+}
+
+// Returns the size that an input image is scaled to, or None when it is not scaled.
+// The output size ('-w' and '-h') is the target size. When only one dimension is given,
+// stretching keeps the other dimension of the input image, while fitting and filling
+// scale by the given dimension alone.
+fn scaled_size(width: u32, height: u32, img_file: &FileParameters) -> Option<(u32, u32)> {
+    let mut ratios: Vec<f64> = vec![];
+    if img_file.width > 0 {
+        ratios.push(img_file.width as f64 / width as f64);
+    }
+    if img_file.height > 0 {
+        ratios.push(img_file.height as f64 / height as f64);
+    }
+    let factor = match img_file.scale {
+        Scale::None => return None,
+        Scale::Stretch => {
+            let stretched = (
+                if img_file.width > 0 { img_file.width as u32 } else { width },
+                if img_file.height > 0 { img_file.height as u32 } else { height }
+            );
+            return if stretched == (width, height) { None } else { Some(stretched) };
+        },
+        Scale::Fit => ratios.iter().cloned().reduce(f64::min)?,
+        Scale::Fill => ratios.iter().cloned().reduce(f64::max)?,
+        Scale::Factor(factor) => factor
+    };
+    let size = (((width as f64 * factor).round() as u32).max(1), ((height as f64 * factor).round() as u32).max(1));
+    if size == (width, height) {
+        None
+    } else {
+        Some(size)
+    }
+}
+
+// Scales an image to the given size, using the given filter. Colors are weighted by
+// their alpha while filtering, so that clear pixels do not darken the edges of an image.
+fn scale_image(img: &image::DynamicImage, width: u32, height: u32, filter: &Filter) -> image::DynamicImage {
+    let mut pixels = img.to_rgba8();
+    let scaled = if *filter == Filter::Nearest {
+        image::imageops::resize(&pixels, width, height, image::imageops::FilterType::Nearest)
+    } else {
+        for pixel in pixels.pixels_mut() {
+            for component in 0..3 {
+                pixel[component] = ((pixel[component] as u32 * pixel[3] as u32 + 127) / 255) as u8;
+            }
+        }
+        let mut scaled = match filter {
+            Filter::Bilinear => image::imageops::resize(&pixels, width, height, image::imageops::FilterType::Triangle),
+            Filter::Lanczos => image::imageops::resize(&pixels, width, height, image::imageops::FilterType::Lanczos3),
+            _ => box_resize(&pixels, width, height)
+        };
+        for pixel in scaled.pixels_mut() {
+            for component in 0..3 {
+                pixel[component] = match pixel[3] {
+                    0 => 0,
+                    alpha => ((pixel[component] as u32 * 255 + alpha as u32 / 2) / alpha as u32).min(255) as u8
+                };
+            }
+        }
+        scaled
+    };
+    if img.color().has_alpha() {
+        image::DynamicImage::ImageRgba8(scaled)
+    } else {
+        image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(scaled).to_rgb8())
+    }
+}
+
+// Scales an image by area averaging: each output pixel is the average of the input
+// pixels that it covers, weighted by how much of each one it covers.
+fn box_resize(img: &image::RgbaImage, width: u32, height: u32) -> image::RgbaImage {
+    let x_ratio = img.width() as f64 / width as f64;
+    let y_ratio = img.height() as f64 / height as f64;
+    let spans = |out: u32, ratio: f64, limit: u32| {
+        let start = out as f64 * ratio;
+        let end = (out + 1) as f64 * ratio;
+        (start.floor() as u32..(end.ceil() as u32).min(limit))
+            .map(|position| (position, (end.min(position as f64 + 1.0) - start.max(position as f64)).max(0.0)))
+            .collect::<Vec<(u32, f64)>>()
+    };
+    let x_spans: Vec<Vec<(u32, f64)>> = (0..width).map(|out_x| spans(out_x, x_ratio, img.width())).collect();
+
+    let mut scaled = image::RgbaImage::new(width, height);
+    for out_y in 0..height {
+        let y_span = spans(out_y, y_ratio, img.height());
+        for (out_x, x_span) in x_spans.iter().enumerate() {
+            let mut sums = [0.0; 4];
+            let mut total = 0.0;
+            for (img_y, y_weight) in &y_span {
+                for (img_x, x_weight) in x_span {
+                    let pixel = img.get_pixel(*img_x, *img_y);
+                    let weight = x_weight * y_weight;
+                    for (sum, component) in sums.iter_mut().zip(pixel.0) {
+                        *sum += component as f64 * weight;
+                    }
+                    total += weight;
+                }
+            }
+            let pixel = sums.map(|sum| if total > 0.0 { (sum / total).round().min(255.0) as u8 } else { 0 });
+            scaled.put_pixel(out_x as u32, out_y, Rgba(pixel));
+        }
+    }
+    scaled
+}
+
+// Writes the timing table of an animation, as 16-bit (little-endian) frame delays
+// in milliseconds, and lists it as assembler source text.
+fn write_timing_table(img_file: &FileParameters, delays: &[u32]) {
+    let uc_path = img_file.base_path();
+    let uc_path = format!("{}.TIM", &uc_path[..uc_path.len() - 4]);
+
+    println!("\n; Frame delays (milliseconds) for {}:", img_file.path);
+    let mut timing_bytes: Vec<u8> = vec![];
+    for (index, delay) in delays.iter().enumerate() {
+        let delay = (*delay).min(0xFFFF) as u16;
+        println!("    DW    {:5}  ; frame {}", delay, index);
+        timing_bytes.extend_from_slice(&delay.to_le_bytes());
+    }
+
+
+    match fs::File::create(uc_path.clone()) {
+        Ok(mut file) => {
+            match file.write_all(&timing_bytes[..]) {
+                Ok(()) => {
+                    println!("Wrote timing file ({}) as {} bytes.", uc_path, timing_bytes.len());
+                },
+                Err(err) => {
+                    println!("ERROR: Cannot write timing file ({}): {}", uc_path, err);
+                }
+            }
+        },
+        Err(err) => {
+            println!("ERROR: Cannot open timing file ({}): {}", uc_path, err);
+        }
+    }
+}
+
+// Writes the animation tags of an Aseprite file as named frame ranges, in assembler source
+// text (also printed to the console), next to the output file.
+fn write_tag_table(img_file: &FileParameters) {
+    let uc_path = img_file.base_path();
+    let stem = &uc_path[..uc_path.len() - 4];
+    let uc_path = format!("{}.TAG", stem);
+    let prefix = stem.rsplit('/').next().unwrap_or("");
+
+    let mut text = format!("; Animation tags (frame ranges) for {}:\n", img_file.path);
+    for (name, first, last) in &img_file.tags {
+        let label = format!("{}_{}", prefix, name).to_ascii_uppercase()
+            .chars().map(|letter| if letter.is_ascii_alphanumeric() { letter } else { '_' })
+            .collect::<String>();
+        text.push_str(&format!("{}_FIRST    EQU    {}\n", label, first));
+        text.push_str(&format!("{}_LAST     EQU    {}\n", label, last));
+    }
+    println!("\n{}", text);
+
+    match fs::File::create(uc_path.clone()) {
+        Ok(mut file) => {
+            match file.write_all(text.as_bytes()) {
+                Ok(()) => {
+                    println!("Wrote tag file ({}) as {} bytes.", uc_path, text.len());
+                },
+                Err(err) => {
+                    println!("ERROR: Cannot write tag file ({}): {}", uc_path, err);
+                }
+            }
+        },
+        Err(err) => {
+            println!("ERROR: Cannot open tag file ({}): {}", uc_path, err);
+        }
+    }
+}
+
+// Reads an image file. PCX and Aseprite files are decoded here; other formats are decoded by the
+// image crate, which chooses the format from the file name extension.
+fn open_image(path: &str) -> Result<image::DynamicImage, String> {
+    if has_extension(path, &["pcx"]) {
+        let data = fs::read(path).map_err(|err| err.to_string())?;
+        decode_pcx(&data)
+    } else if has_extension(path, &ASEPRITE_EXTENSIONS) {
+        let mut aseprite = load_aseprite(path)?;
+        if aseprite.frames.is_empty() {
+            return Err("the file has no frames".to_string());
+        }
+        Ok(image::DynamicImage::ImageRgba8(aseprite.frames.swap_remove(0).0))
+    } else {
+        image::open(path).map_err(|err| err.to_string())
+    }
+}
+
+// Packs pixel indexes (or color values) into bytes, for the given bits-per-pixel,
+// with each pixel row starting on a new byte.
+fn pack_pixels(indexes: &[u8], width: usize, bpp: u8) -> Vec<u8> {
+    let mut output_data: Vec<u8> = vec![];
+    if width == 0 {
+        return output_data;
+    }
+    for row in indexes.chunks(width) {
+        let mut bits_used: u8 = 0;
+        let mut output_byte: u8 = 0;
+        for index in row {
+            if bpp > 4 {
+                output_data.push(*index);
+            } else {
+                output_byte = (output_byte << bpp) | index;
+                bits_used += bpp;
+                if bits_used >= 8 || 8 - bits_used < bpp {
+                    output_data.push(output_byte);
+                    output_byte = 0;
+                    bits_used = 0;
+                }
+            }
+        }
+
+        // finish the pixel row
+        if bits_used > 0 {
+            while bits_used < 8 {
+                output_byte <<= bpp;
+                bits_used += bpp;
+            }
+            output_data.push(output_byte);
+        }
+    }
+    output_data
+}
+
+// Returns the position, within the input image, of the top-left pixel of the output
+// image. The anchor point of the input image is placed over the same point of the output
+// image (by default, the input image is centered), and the input image is then moved
+// by the offset.
+fn image_origin(img_file: &FileParameters, img_width: i32, img_height: i32) -> (i32, i32) {
+    let align = |align: &Align, img_size: i32, out_size: i32| match align {
+        Align::Start => 0,
+        Align::Center => img_size / 2 - out_size / 2,
+        Align::End => img_size - out_size
+    };
+    (align(&img_file.anchor.horizontal, img_width, img_file.width as i32) - img_file.offset.0,
+        align(&img_file.anchor.vertical, img_height, img_file.height as i32) - img_file.offset.1)
+}
+
+// Returns the tight bounding box of the pixels of an image that are not transparent
+// (neither fully clear, nor transparent because of '-a' or '-k'), or None when every
+// pixel is transparent.
+fn trim_box(img: &image::DynamicImage, img_file: &FileParameters) -> Option<Trim> {
+    let rgba = img.to_rgba8();
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        if pixel[IMG_A] == 0 || is_transparent(pixel, img_file) {
+            continue;
+        }
+        bounds = Some(match bounds {
+            Some((left, top, right, bottom)) => (left.min(x), top.min(y), right.max(x), bottom.max(y)),
+            None => (x, y, x, y)
+        });
+    }
+    bounds.map(|(left, top, right, bottom)| Trim {
+        x: left,
+        y: top,
+        width: right + 1 - left,
+        height: bottom + 1 - top,
+        original_width: rgba.width(),
+        original_height: rgba.height()
+    })
+}
+
+// Returns whether a pixel is transparent (output as index zero), because its alpha
+// is below the file's alpha threshold, or because it matches the file's chroma key.
+fn is_transparent(pixel: &Rgba<u8>, img_file: &FileParameters) -> bool {
+    pixel[IMG_A] < img_file.alpha_threshold ||
+        img_file.chroma_key == Some(Rgb::<u8>([pixel[IMG_R], pixel[IMG_G], pixel[IMG_B]]))
+}
+
+// Returns the pixels of the input image. For an indexed file with a background color,
+// every pixel that is not fully opaque (and not transparent, as given by the alpha
+// threshold and chroma key) is composited against the background, and becomes opaque.
+fn flatten_pixels(img: &image::DynamicImage, img_file: &FileParameters) -> image::RgbaImage {
+    let mut rgba = img.to_rgba8();
+    let background = match img_file.background {
+        Background::Color(color) if img_file.bpp != 8 => color,
+        _ => return rgba
+    };
+    for pixel in rgba.pixels_mut() {
+        let alpha = pixel[IMG_A] as u32;
+        if alpha == 255 || is_transparent(pixel, img_file) {
+            continue;
+        }
+        for (component, channel) in [IMG_R, IMG_G, IMG_B].into_iter().enumerate() {
+            let blended = pixel[channel] as u32 * alpha + background[component] as u32 * (255 - alpha);
+            pixel[channel] = ((blended + 127) / 255) as u8;
+        }
+        pixel[IMG_A] = 255;
+    }
+    rgba
+}
+
+// Returns the most common fully opaque color along the border of the input image,
+// preferring the color found first when there is a tie. Returns black when the
+// border has no opaque pixels.
+fn border_color(img: &image::DynamicImage, img_file: &FileParameters) -> Rgb<u8> {
+    let rgba = img.to_rgba8();
+    let width = rgba.width();
+    let height = rgba.height();
+    let mut counts: Vec<(Rgb<u8>, usize)> = vec![];
+    for y in 0..height {
+        for x in 0..width {
+            if x > 0 && x + 1 < width && y > 0 && y + 1 < height {
+                continue; // not on the border
+            }
+            let pixel = rgba.get_pixel(x, y);
+            if pixel[IMG_A] < 255 || is_transparent(pixel, img_file) {
+                continue;
+            }
+            let color = Rgb::<u8>([pixel[IMG_R], pixel[IMG_G], pixel[IMG_B]]);
+            match counts.iter_mut().find(|(entry, _count)| *entry == color) {
+                Some((_entry, count)) => *count += 1,
+                None => counts.push((color, 1))
+            }
+        }
+    }
+    let mut best = (Rgb::<u8>([0, 0, 0]), 0);
+    for (color, count) in counts {
+        if count > best.1 {
+            best = (color, count);
+        }
+    }
+    best.0
+}
+
+// Converts every pixel of the input image to a 6-bit color, applying the
+// file's quantization and dithering options. The result is in row order,
+// with None for transparent pixels. Transparent and fully clear pixels
+// neither receive nor spread dithering error. Ordered dithering is anchored
+// to output coordinates, so that padded or cropped outputs line up with each other.
+fn convert_pixels(img: &image::DynamicImage, img_file: &FileParameters) -> Vec<Option<Rgb<u8>>> {
+    let rgba = flatten_pixels(img, img_file);
+    let width = rgba.width() as i32;
+    let height = rgba.height() as i32;
+    let (kernel, divisor) = img_file.dither.kernel();
+
+    // Ordered dithering follows the position of the output image over the input image.
+    let (origin_x, origin_y) = image_origin(img_file, width, height);
+
+    // Accumulated error for each color component of each pixel.
+    let mut errors: Vec<[i32; 3]> = vec![[0; 3]; (width * height) as usize];
+    let mut colors: Vec<Option<Rgb<u8>>> = Vec::with_capacity((width * height) as usize);
+    let is_clear = |pixel: &Rgba<u8>| pixel[IMG_A] == 0 || is_transparent(pixel, img_file);
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = rgba.get_pixel(x as u32, y as u32);
+            let offset = (y * width + x) as usize;
+            let opaque = !is_clear(pixel);
+            let mut color = Rgb::<u8>([0, 0, 0]);
+            let mut error = [0i32; 3];
+            let bias = match img_file.dither {
+                Dither::Ordered(size) => ordered_offset(img_file, &img_file.quantize, size, x - origin_x, y - origin_y),
+                _ => 0
+            };
+            for (component, channel) in [IMG_R, IMG_G, IMG_B].into_iter().enumerate() {
+                let value = if opaque {
+                    (pixel[channel] as i32 + errors[offset][component] + bias).clamp(0, 255)
+                } else {
+                    pixel[channel] as i32
+                };
+                color[component] = convert_color(value as u8, &img_file.quantize);
+                error[component] = value - widen_component(color[component]) as i32;
+            }
+            if is_transparent(pixel, img_file) {
+                colors.push(None);
+            } else {
+                colors.push(Some(color));
+            }
+
+            if !opaque {
+                continue;
+            }
+            for (dx, dy, weight) in kernel {
+                let nx = x + dx;
+                let ny = y + dy;
+                if nx < 0 || nx >= width || ny >= height {
+                    continue;
+                }
+                if is_clear(rgba.get_pixel(nx as u32, ny as u32)) {
+                    continue;
+                }
+                let neighbor = &mut errors[(ny * width + nx) as usize];
+                for component in 0..3 {
+                    neighbor[component] += error[component] * weight / divisor;
+                }
+            }
+        }
+    }
+    colors
+}
+
+// Converts the alpha value of every pixel of the input image to a 2-bit level,
+// applying the file's alpha quantization and alpha dithering options. The result
+// is in row order. Only partially transparent pixels receive and spread dithering
+// error, so that fully opaque and fully clear areas keep their exact alpha levels.
+fn convert_alpha(img: &image::DynamicImage, img_file: &FileParameters) -> Vec<u8> {
+    let rgba = img.to_rgba8();
+    let width = rgba.width() as i32;
+    let height = rgba.height() as i32;
+    let (kernel, divisor) = img_file.alpha_dither.kernel();
+
+    // Ordered dithering follows the position of the output image over the input image.
+    let (origin_x, origin_y) = image_origin(img_file, width, height);
+
+    // Accumulated alpha error for each pixel.
+    let mut errors: Vec<i32> = vec![0; (width * height) as usize];
+    let mut alphas: Vec<u8> = Vec::with_capacity((width * height) as usize);
+    let is_partial = |pixel: &Rgba<u8>| {
+        pixel[IMG_A] > 0 && pixel[IMG_A] < 255 && !is_transparent(pixel, img_file)
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = rgba.get_pixel(x as u32, y as u32);
+            let offset = (y * width + x) as usize;
+            if !is_partial(pixel) {
+                alphas.push(convert_color(pixel[IMG_A], &img_file.alpha_quantize));
+                continue;
+            }
+            let bias = match img_file.alpha_dither {
+                Dither::Ordered(size) => ordered_offset(img_file, &img_file.alpha_quantize, size, x - origin_x, y - origin_y),
+                _ => 0
+            };
+            let value = (pixel[IMG_A] as i32 + errors[offset] + bias).clamp(0, 255);
+            let level = convert_color(value as u8, &img_file.alpha_quantize);
+            let error = value - widen_component(level) as i32;
+            alphas.push(level);
+
+            for (dx, dy, weight) in kernel {
+                let nx = x + dx;
+                let ny = y + dy;
+                if nx < 0 || nx >= width || ny >= height {
+                    continue;
+                }
+                if !is_partial(rgba.get_pixel(nx as u32, ny as u32)) {
+                    continue;
+                }
+                errors[(ny * width + nx) as usize] += error * weight / divisor;
+            }
+        }
+    }
+    alphas
+}
+
+// Fills in the (possibly remapped) colors that a file uses, from its histogram.
+fn collect_colors(img_file: &mut FileParameters) {
+    img_file.colors.clear();
+    for (color, _count) in &img_file.histogram {
+        let color = *img_file.remap.get(color).unwrap_or(color);
+        if !img_file.colors.contains_key(&color) {
+            if img_file.bpp == 8 {
+                let index = (color[2]<<4)|(color[1]<<2)|color[0];
+                img_file.colors.insert(color, index);    
+            } else {
+                let index = (img_file.colors.len() + 1) as u8;
+                img_file.colors.insert(color, index);    
+            }
+        }
+    }
+}
+
+// Returns the (possibly remapped) colors that a file uses, in order of first appearance.
+fn ordered_colors(img_file: &FileParameters) -> Vec<Rgb<u8>> {
+    let mut colors: Vec<Rgb<u8>> = vec![];
+    for (color, _count) in &img_file.histogram {
+        let color = *img_file.remap.get(color).unwrap_or(color);
+        if !colors.contains(&color) {
+            colors.push(color);
+        }
+    }
+    colors
+}
+
+// Sorts colors (given in order of first appearance) into the requested palette order.
+// The sort is stable, so colors that compare equal keep their order of appearance.
+fn sort_palette_colors(colors: &mut [Rgb<u8>], files: &[FileParameters], order: &Order) {
+    match order {
+        Order::Appearance => {},
+        Order::Frequency => {
+            let mut counts: HashMap<Rgb<u8>, usize> = HashMap::new();
+            for img_file in files.iter().filter(|img_file| img_file.bpp != 8) {
+                for (color, count) in &img_file.histogram {
+                    *counts.entry(*img_file.remap.get(color).unwrap_or(color)).or_insert(0) += count;
+                }
+            }
+            colors.sort_by_key(|color| std::cmp::Reverse(counts.get(color).copied().unwrap_or(0)));
+        },
+        Order::Luminance => {
+            colors.sort_by(|color1, color2| luminance(color1).total_cmp(&luminance(color2)));
+        },
+        Order::Hue => {
+            colors.sort_by(|color1, color2| {
+                hue(color1).total_cmp(&hue(color2))
+                    .then(luminance(color1).total_cmp(&luminance(color2)))
+            });
+        },
+        Order::Explicit(listed) => {
+            colors.sort_by_key(|color| listed.iter().position(|entry| entry == color).unwrap_or(listed.len()));
+        }
+    }
+}
+
+// Returns the relative luminance of a 6-bit color (0.0 to 255.0).
+fn luminance(color: &Rgb<u8>) -> f64 {
+    let wcolor = widen_components(color);
+    0.299 * wcolor[0] + 0.587 * wcolor[1] + 0.114 * wcolor[2]
+}
+
+// Returns the hue angle of a 6-bit color (0.0 to 360.0), or -1.0 for a gray.
+fn hue(color: &Rgb<u8>) -> f64 {
+    let [r, g, b] = widen_components(color);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    if max == min {
+        return -1.0;
+    }
+    let delta = max - min;
+    let sector = if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    sector * 60.0
+}
+
+// Parses a palette index (1 to 63), which must not be the transparent index (zero).
+fn parse_palette_index(text: &str) -> Result<usize, String> {
+    match text.trim().parse::<usize>() {
+        Ok(index) if (1..64).contains(&index) => Ok(index),
+        _ => Err(format!("invalid palette index '{}' (expected 1 to 63)", text))
+    }
+}
+
+// Parses pinned palette entries, such as "1=333" or "1=333,2=300".
+fn parse_pins(text: &str) -> Result<Vec<(usize, Rgb<u8>)>, String> {
+    let mut pins: Vec<(usize, Rgb<u8>)> = vec![];
+    for part in text.split(',') {
+        let Some((index, color)) = part.split_once('=') else {
+            return Err(format!("expected index=RGB, found '{}'", part));
+        };
+        pins.push((parse_palette_index(index)?, parse_rgb222(color)?));
+    }
+    Ok(pins)
+}
+
+// Parses reserved palette indexes, such as "5", "40-47", or "5,40-47".
+fn parse_reserved(text: &str) -> Result<Vec<usize>, String> {
+    let mut reserved: Vec<usize> = vec![];
+    for part in text.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first = parse_palette_index(first)?;
+                let last = parse_palette_index(last)?;
+                if first > last {
+                    return Err(format!("invalid index range '{}'", part));
+                }
+                reserved.extend(first..=last);
+            },
+            None => {
+                reserved.push(parse_palette_index(part)?);
+            }
+        }
+    }
+    Ok(reserved)
+}
+
+fn parse_order(text: &str) -> Result<Order, String> {
+    match text {
+        "appearance" => Ok(Order::Appearance),
+        "frequency" => Ok(Order::Frequency),
+        "luminance" => Ok(Order::Luminance),
+        "hue" => Ok(Order::Hue),
+        _ => {
+            // Expect an explicit list of colors, such as "333,300,030".
+            let mut listed: Vec<Rgb<u8>> = vec![];
+            for part in text.split(',') {
+                listed.push(parse_rgb222(part).map_err(|_|
+                    format!("expected appearance, frequency, luminance, hue, or a list of RGB colors, found '{}'", text))?);
+            }
+            Ok(Order::Explicit(listed))
+        }
+    }
+}
+
+// Parses a 24-bit color given as 6 hexadecimal digits, such as "ff00ff" or "#ff00ff".
+fn parse_rgb888(text: &str) -> Result<Rgb<u8>, String> {
+    let digits = text.trim().trim_start_matches('#');
+    if digits.len() != 6 || !digits.chars().all(|digit| digit.is_ascii_hexdigit()) {
+        return Err(format!("invalid RGB color '{}' (expected 6 hexadecimal digits)", text));
+    }
+    let component = |position: usize| u8::from_str_radix(&digits[position..position + 2], 16).unwrap();
+    Ok(Rgb::<u8>([component(0), component(2), component(4)]))
+}
+
+// Parses a 6-bit color given as 3 digits (0 to 3) for red, green, and blue, such as "302".
+fn parse_rgb222(text: &str) -> Result<Rgb<u8>, String> {
+    let digits = text.trim().chars().map(|digit| digit.to_digit(4)).collect::<Vec<Option<u32>>>();
+    if digits.len() != 3 || digits.iter().any(|digit| digit.is_none()) {
+        return Err(format!("invalid RGB color '{}' (expected 3 digits, 0 to 3)", text));
+    }
+    Ok(Rgb::<u8>([digits[0].unwrap() as u8, digits[1].unwrap() as u8, digits[2].unwrap() as u8]))
+}
+
+// Prints how many colors were merged by a color reduction, and the mean error per pixel.
+fn report_reduction(label: &str, histogram: &[(Rgb<u8>, usize)], remap: &HashMap<Rgb<u8>, Rgb<u8>>, metric: &Metric) {
+    let mut total_error = 0.0;
+    let mut total_pixels = 0;
+    for (color, count) in histogram {
+        let mapped = remap.get(color).unwrap_or(color);
+        total_error += color_distance(color, mapped, metric) * (*count as f64);
+        total_pixels += count;
+    }
+    let mut kept: Vec<Rgb<u8>> = histogram.iter()
+        .map(|(color, _)| *remap.get(color).unwrap_or(color))
+        .collect();
+    kept.sort_by_key(|color| color.0);
+    kept.dedup();
+    println!("{} reduced from {} to {} colors ({} merged), mean error {:.2}.",
+        label, histogram.len(), kept.len(), histogram.len() - kept.len(),
+        total_error / (total_pixels.max(1) as f64));
+}
+
+fn parse_background(text: &str) -> Result<Background, String> {
+    match text {
+        "none" => Ok(Background::None),
+        "auto" => Ok(Background::Auto),
+        _ => Ok(Background::Color(parse_rgb888(text)?))
+    }
+}
+
+fn parse_animation(text: &str) -> Result<Animation, String> {
+    match text {
+        "first" => Ok(Animation::First),
+        "files" => Ok(Animation::Files),
+        "joined" => Ok(Animation::Joined),
+        _ => Err(format!("expected first, files, or joined, found '{}'", text))
+    }
+}
+
+// Parses a scaling mode: stretch, fit, fill, or a scale factor (such as 2 or 0.5).
+fn parse_scale(text: &str) -> Result<Scale, String> {
+    match text {
+        "none" => Ok(Scale::None),
+        "stretch" => Ok(Scale::Stretch),
+        "fit" => Ok(Scale::Fit),
+        "fill" => Ok(Scale::Fill),
+        _ => match text.parse::<f64>() {
+            Ok(factor) if factor > 0.0 && factor.is_finite() => Ok(Scale::Factor(factor)),
+            _ => Err(format!("expected none, stretch, fit, fill, or a positive factor, found '{}'", text))
+        }
+    }
+}
+
+fn parse_filter(text: &str) -> Result<Filter, String> {
+    match text {
+        "nearest" => Ok(Filter::Nearest),
+        "box" => Ok(Filter::Box),
+        "bilinear" => Ok(Filter::Bilinear),
+        "lanczos" => Ok(Filter::Lanczos),
+        _ => Err(format!("expected nearest, box, bilinear, or lanczos, found '{}'", text))
+    }
+}
+
+// Parses an anchor, such as "center", "top-left", "bottom" (or "bottom-center"), or "right".
+fn parse_anchor(text: &str) -> Result<Anchor, String> {
+    let mut anchor = Anchor { horizontal: Align::Center, vertical: Align::Center };
+    for part in text.split('-') {
+        match part {
+            "top" => anchor.vertical = Align::Start,
+            "bottom" => anchor.vertical = Align::End,
+            "left" => anchor.horizontal = Align::Start,
+            "right" => anchor.horizontal = Align::End,
+            "center" | "middle" => {},
+            _ => return Err(format!("expected center, or a combination of top, bottom, left, and right (such as top-left), found '{}'", text))
+        }
+    }
+    Ok(anchor)
+}
+
+// Parses an offset, such as "4,-2" (in pixels).
+fn parse_offset(text: &str) -> Result<(i32, i32), String> {
+    let (x, y) = text.split_once(',')
+        .ok_or(format!("expected x,y such as 4,-2, found '{}'", text))?;
+    let number = |part: &str| part.trim().parse::<i32>().map_err(|err| err.to_string());
+    Ok((number(x)?, number(y)?))
+}
+
+// Parses a source rectangle, such as "0,0,64,32", or "0,0,64,32,hero" (with a name for its output file).
+fn parse_source(text: &str) -> Result<Source, String> {
+    let parts = text.split(',').collect::<Vec<&str>>();
+    if parts.len() < 4 || parts.len() > 5 {
+        return Err(format!("expected x,y,w,h[,name], found '{}'", text));
+    }
+    let number = |part: &str| part.trim().parse::<u32>().map_err(|err| err.to_string());
+    let source = Source {
+        x: number(parts[0])?,
+        y: number(parts[1])?,
+        width: number(parts[2])?,
+        height: number(parts[3])?,
+        name: parts.get(4).map(|name| name.trim().to_string())
+    };
+    if source.width == 0 || source.height == 0 {
+        return Err(format!("expected a non-zero size, found '{}'", text));
+    }
+    if source.name.as_ref().is_some_and(|name| name.is_empty() || name.contains(['/', '.'])) {
+        return Err(format!("expected a file name without a directory or extension, found '{}'", text));
+    }
+    Ok(source)
+}
+
+// Parses a slice, such as "16x16", "16x16,1" (with a margin), or "16x16,1,2" (with a margin and spacing).
+fn parse_slice(text: &str) -> Result<Slice, String> {
+    let parts = text.split(',').collect::<Vec<&str>>();
+    let (width, height) = parts[0].split_once('x')
+        .ok_or(format!("expected a cell size such as 16x16, found '{}'", parts[0]))?;
+    let number = |part: &str| part.trim().parse::<u32>().map_err(|err| err.to_string());
+    let slice = Slice {
+        width: number(width)?,
+        height: number(height)?,
+        margin: if parts.len() > 1 { number(parts[1])? } else { 0 },
+        spacing: if parts.len() > 2 { number(parts[2])? } else { 0 }
+    };
+    if parts.len() > 3 || slice.width == 0 || slice.height == 0 {
+        return Err(format!("expected WxH[,margin[,spacing]], with a non-zero cell size, found '{}'", text));
+    }
+    Ok(slice)
+}
+
+// Parses atlas limits, such as "256x256" or "256x256,1" (with padding).
+fn parse_atlas(text: &str) -> Result<AtlasLimits, String> {
+    let parts = text.split(',').collect::<Vec<&str>>();
+    let (width, height) = parts[0].split_once('x')
+        .ok_or(format!("expected a maximum size such as 256x256, found '{}'", parts[0]))?;
+    let number = |part: &str| part.trim().parse::<u32>().map_err(|err| err.to_string());
+    let limits = AtlasLimits {
+        width: number(width)?,
+        height: number(height)?,
+        padding: if parts.len() > 1 { number(parts[1])? } else { 0 }
+    };
+    if parts.len() > 2 || limits.width == 0 || limits.height == 0 {
+        return Err(format!("expected WxH[,padding], with a non-zero size, found '{}'", text));
+    }
+    Ok(limits)
+}
+
+fn parse_reduce(text: &str) -> Result<Reduce, String> {
+    match text {
+        "none" => Ok(Reduce::None),
+        "mediancut" | "median-cut" => Ok(Reduce::MedianCut),
+        "kmeans" | "k-means" => Ok(Reduce::KMeans),
+        _ => Err(format!("expected none, mediancut, or kmeans, found '{}'", text))
+    }
+}
+
+// Reads the palette of an indexed PNG file, or takes the palette of an indexed Aseprite
+// file (read when the file was added), returning its colors (in palette order) as 6-bit
+// colors, using the quantization of the file. Returns None for other files.
+fn load_file_palette(img_file: &FileParameters) -> Result<Option<Vec<Rgb<u8>>>, String> {
+    let path = &img_file.path;
+    let quantize = &img_file.quantize;
+    if has_extension(path, &ASEPRITE_EXTENSIONS) {
+        return Ok(img_file.aseprite_palette.as_ref().map(|palette| {
+            palette.iter().map(|entry| Rgb::<u8>([
+                convert_color(entry[0], quantize),
+                convert_color(entry[1], quantize),
+                convert_color(entry[2], quantize)
+            ])).collect()
+        }));
+    }
+    if !has_extension(path, &["png"]) {
+        return Ok(None);
+    }
+    let file = fs::File::open(path).map_err(|err| err.to_string())?;
+    let reader = png::Decoder::new(file).read_info().map_err(|err| err.to_string())?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed {
+        return Ok(None);
+    }
+    Ok(info.palette.as_ref().map(|palette| {
+        palette.chunks(3).map(|entry| Rgb::<u8>([
+            convert_color(entry[0], quantize),
+            convert_color(entry[1], quantize),
+            convert_color(entry[2], quantize)
+        ])).collect()
+    }))
+}
+
+// Loads a palette file, returning the 64 palette entries as 6-bit colors. The file
+// may be a PALETTE.BIN file written by this program, a GIMP palette (.gpl), a JASC
+// palette (.pal), or an Adobe color table (.act). Entries beyond 64 are ignored,
+// and entries missing from the file are returned as None.
+fn load_palette(path: &str) -> Result<Vec<Option<Rgb<u8>>>, String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    let lc_path = path.to_ascii_lowercase();
+
+    // Read 8-bit colors, except for PALETTE.BIN, which has 2-bit color components.
+    let mut colors: Vec<Rgb<u8>> = vec![];
+    if lc_path.ends_with(".bin") {
+        if data.len() % 3 != 0 {
+            return Err("size is not a multiple of 3 bytes".to_string());
+        }
+        for entry in data.chunks(3) {
+            if entry.iter().any(|component| *component > 3) {
+                return Err("color components must be 0 to 3".to_string());
+            }
+            colors.push(Rgb::<u8>([entry[0], entry[1], entry[2]]));
+        }
+    } else {
+        let colors8 = if lc_path.ends_with(".gpl") {
+            parse_gpl_palette(&data)?
+        } else if lc_path.ends_with(".pal") {
+            parse_jasc_palette(&data)?
+        } else if lc_path.ends_with(".act") {
+            parse_act_palette(&data)?
+        } else {
+            return Err("expected a .bin, .gpl, .pal, or .act file".to_string());
+        };
+        for color in colors8 {
+            colors.push(Rgb::<u8>([
+                convert_color(color[0], &Quantize::Nearest),
+                convert_color(color[1], &Quantize::Nearest),
+                convert_color(color[2], &Quantize::Nearest)]));
+        }
+    }
+
+    if colors.len() < 2 {
+        return Err("no palette colors found".to_string());
+    }
+    if colors.len() > 64 {
+        println!("Palette file {} has {} entries; using the first 64.", path, colors.len());
+    }
+    let mut palette: Vec<Option<Rgb<u8>>> = colors.into_iter().take(64).map(Some).collect();
+    palette.resize(64, None);
+    Ok(palette)
+}
+
+// Returns the color that a previous palette (see '-i') has at a palette index, when
+// it differs from the given color, meaning that the index cannot take that color.
+fn previous_color(global: &GlobalParameters, index: usize, color: &Rgb<u8>) -> Option<Rgb<u8>> {
+    global.previous.as_ref()
+        .and_then(|previous| previous.get(index).copied().flatten())
+        .filter(|previous| previous != color)
+}
+
+// Parses three 8-bit color components from text, such as "255 128 0".
+fn parse_palette_line(line: &str) -> Result<Rgb<u8>, String> {
+    let parts = line.split_whitespace().take(3).collect::<Vec<&str>>();
+    if parts.len() != 3 {
+        return Err(format!("invalid color entry '{}'", line));
+    }
+    let mut color = Rgb::<u8>([0, 0, 0]);
+    for (component, part) in parts.iter().enumerate() {
+        color[component] = part.parse::<u8>().map_err(|err| format!("invalid color entry '{}': {}", line, err))?;
+    }
+    Ok(color)
+}
+
+fn parse_gpl_palette(data: &[u8]) -> Result<Vec<Rgb<u8>>, String> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines();
+    if lines.next().map(|line| line.trim()) != Some("GIMP Palette") {
+        return Err("missing 'GIMP Palette' header".to_string());
+    }
+    let mut colors: Vec<Rgb<u8>> = vec![];
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.contains(':') {
+            continue; // comments, and header lines such as "Name: ..." and "Columns: ..."
+        }
+        colors.push(parse_palette_line(line)?);
+    }
+    Ok(colors)
+}
+
+fn parse_jasc_palette(data: &[u8]) -> Result<Vec<Rgb<u8>>, String> {
+    let text = String::from_utf8_lossy(data);
+    let lines = text.lines().map(|line| line.trim()).collect::<Vec<&str>>();
+    if lines.len() < 3 || lines[0] != "JASC-PAL" {
+        return Err("missing 'JASC-PAL' header".to_string());
+    }
+    let count = lines[2].parse::<usize>().map_err(|err| format!("invalid color count: {}", err))?;
+    let mut colors: Vec<Rgb<u8>> = vec![];
+    for line in lines.iter().skip(3).filter(|line| !line.is_empty()).take(count) {
+        colors.push(parse_palette_line(line)?);
+    }
+    if colors.len() < count {
+        return Err(format!("expected {} colors, found {}", count, colors.len()));
+    }
+    Ok(colors)
+}
+
+fn parse_act_palette(data: &[u8]) -> Result<Vec<Rgb<u8>>, String> {
+    if data.len() < 768 {
+        return Err("an Adobe color table must have at least 768 bytes".to_string());
+    }
+    // An optional trailer gives the number of colors actually used.
+    let mut count = 256;
+    if data.len() >= 772 {
+        let used = ((data[768] as usize) << 8) | (data[769] as usize);
+        if used > 0 && used <= 256 {
+            count = used;
+        }
+    }
+    Ok(data[..count * 3].chunks(3).map(|entry| Rgb::<u8>([entry[0], entry[1], entry[2]])).collect())
+}
+
+fn parse_metric(text: &str) -> Result<Metric, String> {
+    match text {
+        "rgb" => Ok(Metric::Rgb),
+        "redmean" => Ok(Metric::Redmean),
+        "lab" | "lab76" | "de76" => Ok(Metric::Lab76),
+        "lab2000" | "de2000" => Ok(Metric::Lab2000),
+        _ => Err(format!("expected rgb, redmean, lab76, or lab2000, found '{}'", text))
+    }
+}
+
+// Adds the 8-bit components of a pixel to the sum for the 6-bit color it was converted to.
+fn add_pixel_color(sums: &mut HashMap<Rgb<u8>, [f64; 3]>, color: &Rgb<u8>, pixel: &Rgba<u8>) {
+    let sum = sums.entry(*color).or_insert([0.0; 3]);
+    for (component, channel) in [IMG_R, IMG_G, IMG_B].into_iter().enumerate() {
+        sum[component] += pixel[channel] as f64;
+    }
+}
+
+// Returns the widened components of a 6-bit color, as floating point values.
+fn widen_components(color: &Rgb<u8>) -> [f64; 3] {
+    let wcolor = widen_color(color);
+    [wcolor[0] as f64, wcolor[1] as f64, wcolor[2] as f64]
+}
+
+// Returns the distance between two 6-bit colors, using their widened components.
+fn color_distance(color1: &Rgb<u8>, color2: &Rgb<u8>, metric: &Metric) -> f64 {
+    component_distance(&widen_components(color1), &widen_components(color2), metric)
+}
+
+// Returns the distance between two colors given by 8-bit (0.0 to 255.0) components.
+fn component_distance(color1: &[f64; 3], color2: &[f64; 3], metric: &Metric) -> f64 {
+    match metric {
+        Metric::Rgb => {
+            let mut sum = 0.0;
+            for component in 0..3 {
+                let delta = color1[component] - color2[component];
+                sum += delta * delta;
+            }
+            sum.sqrt()
+        },
+        Metric::Redmean => {
+            let red_mean = (color1[0] + color2[0]) / 2.0;
+            let dr = color1[0] - color2[0];
+            let dg = color1[1] - color2[1];
+            let db = color1[2] - color2[2];
+            ((2.0 + red_mean / 256.0) * dr * dr +
+                4.0 * dg * dg +
+                (2.0 + (255.0 - red_mean) / 256.0) * db * db).sqrt()
+        },
+        Metric::Lab76 => {
+            let lab1 = rgb_to_lab(color1);
+            let lab2 = rgb_to_lab(color2);
+            let mut sum = 0.0;
+            for component in 0..3 {
+                let delta = lab1[component] - lab2[component];
+                sum += delta * delta;
+            }
+            sum.sqrt()
+        },
+        Metric::Lab2000 => {
+            delta_e_2000(&rgb_to_lab(color1), &rgb_to_lab(color2))
+        }
+    }
+}
+
+// Converts an sRGB color (0.0 to 255.0 components) to CIELAB, using the D65 white point.
+fn rgb_to_lab(color: &[f64; 3]) -> [f64; 3] {
+    let linear = |value: f64| {
+        let value = value / 255.0;
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let r = linear(color[0]);
+    let g = linear(color[1]);
+    let b = linear(color[2]);
+
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let fx = f(x);
+    let fy = f(y);
+    let fz = f(z);
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// Returns the CIEDE2000 color difference between two CIELAB colors.
+fn delta_e_2000(lab1: &[f64; 3], lab2: &[f64; 3]) -> f64 {
+    let (l1, a1, b1) = (lab1[0], lab1[1], lab1[2]);
+    let (l2, a2, b2) = (lab2[0], lab2[1], lab2[2]);
+
+    let c1 = (a1 * a1 + b1 * b1).sqrt();
+    let c2 = (a2 * a2 + b2 * b2).sqrt();
+    let c_mean = (c1 + c2) / 2.0;
+    let c_mean7 = c_mean.powi(7);
+    let g = 0.5 * (1.0 - (c_mean7 / (c_mean7 + 25.0f64.powi(7))).sqrt());
+
+    let a1p = (1.0 + g) * a1;
+    let a2p = (1.0 + g) * a2;
+    let c1p = (a1p * a1p + b1 * b1).sqrt();
+    let c2p = (a2p * a2p + b2 * b2).sqrt();
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let h1p = hue(b1, a1p);
+    let h2p = hue(b2, a2p);
+
+    let dlp = l2 - l1;
+    let dcp = c2p - c1p;
+    let dhp = if c1p * c2p == 0.0 {
+        0.0
+    } else if (h2p - h1p).abs() <= 180.0 {
+        h2p - h1p
+    } else if h2p - h1p > 180.0 {
+        h2p - h1p - 360.0
+    } else {
+        h2p - h1p + 360.0
+    };
+    let dhp = 2.0 * (c1p * c2p).sqrt() * (dhp / 2.0).to_radians().sin();
+
+    let lp_mean = (l1 + l2) / 2.0;
+    let cp_mean = (c1p + c2p) / 2.0;
+    let hp_mean = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (hp_mean - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * hp_mean).to_radians().cos()
+        + 0.32 * (3.0 * hp_mean + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * hp_mean - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((hp_mean - 275.0) / 25.0).powi(2)).exp();
+    let cp_mean7 = cp_mean.powi(7);
+    let r_c = 2.0 * (cp_mean7 / (cp_mean7 + 25.0f64.powi(7))).sqrt();
+    let lp_offset = (lp_mean - 50.0) * (lp_mean - 50.0);
+    let s_l = 1.0 + 0.015 * lp_offset / (20.0 + lp_offset).sqrt();
+    let s_c = 1.0 + 0.045 * cp_mean;
+    let s_h = 1.0 + 0.015 * cp_mean * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+    let l_term = dlp / s_l;
+    let c_term = dcp / s_c;
+    let h_term = dhp / s_h;
+    (l_term * l_term + c_term * c_term + h_term * h_term + r_t * c_term * h_term).sqrt()
+}
+
+// Returns the 6-bit color nearest to a color given by widened components.
+fn nearest_level_color(components: &[f64; 3], metric: &Metric) -> Rgb<u8> {
+    let mut best = Rgb::<u8>([0, 0, 0]);
+    let mut best_distance = f64::MAX;
+    for index in 0..64u8 {
+        let candidate = Rgb::<u8>([index & 3, (index >> 2) & 3, index >> 4]);
+        let distance = component_distance(components, &widen_components(&candidate), metric);
+        if distance < best_distance {
+            best = candidate;
+            best_distance = distance;
+        }
+    }
+    best
+}
+
+// Returns the pixel-weighted mean of a set of colors, as widened components.
+fn mean_color(histogram: &[(Rgb<u8>, usize)]) -> [f64; 3] {
+    let mut sums = [0.0; 3];
+    let mut total = 0.0;
+    for (color, count) in histogram {
+        let wcolor = widen_color(color);
+        for component in 0..3 {
+            sums[component] += wcolor[component] as f64 * (*count as f64);
+        }
+        total += *count as f64;
+    }
+    if total > 0.0 {
+        for sum in &mut sums {
+            *sum /= total;
+        }
+    }
+    sums
+}
+
+// Splits a color histogram into (up to) the given number of boxes, by repeatedly
+// cutting the box with the widest component range at its pixel-weighted median.
+fn median_cut(histogram: &[(Rgb<u8>, usize)], max_colors: usize) -> Vec<Vec<(Rgb<u8>, usize)>> {
+    let mut boxes: Vec<Vec<(Rgb<u8>, usize)>> = vec![histogram.to_vec()];
+    while boxes.len() < max_colors {
+        // Find the box and component with the widest range.
+        let mut widest: Option<(usize, usize, u8)> = None;
+        for (box_index, colors) in boxes.iter().enumerate() {
+            if colors.len() < 2 {
+                continue;
+            }
+            for component in 0..3 {
+                let min = colors.iter().map(|(color, _)| color[component]).min().unwrap();
+                let max = colors.iter().map(|(color, _)| color[component]).max().unwrap();
+                let range = max - min;
+                if range > 0 && widest.is_none_or(|(_, _, widest_range)| range > widest_range) {
+                    widest = Some((box_index, component, range));
+                }
+            }
+        }
+        let Some((box_index, component, _)) = widest else {
+            break; // every box holds a single color
+        };
+
+        // Cut the box at the median pixel.
+        let mut colors = boxes.swap_remove(box_index);
+        colors.sort_by_key(|(color, _)| (color[component], color.0));
+        let total: usize = colors.iter().map(|(_, count)| count).sum();
+        let mut running = 0;
+        let mut split = 1;
+        for (position, (_, count)) in colors.iter().enumerate() {
+            running += count;
+            if running * 2 >= total {
+                split = (position + 1).clamp(1, colors.len() - 1);
+                break;
+            }
+        }
+        // Do not separate colors that share the cut component value.
+        let value_at = |position: usize| colors[position].0[component];
+        let mut cut = split;
+        while cut < colors.len() && value_at(cut) == value_at(cut - 1) {
+            cut += 1;
+        }
+        if cut == colors.len() {
+            cut = split;
+            while cut > 1 && value_at(cut) == value_at(cut - 1) {
+                cut -= 1;
+            }
+        }
+        let upper = colors.split_off(cut);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+    boxes
+}
+
+// Chooses (up to) the given number of colors to represent a color histogram, and
+// returns a map from each original color to its replacement color. If 'existing'
+// is true, the chosen colors are taken from the histogram itself.
+fn reduce_colors(histogram: &[(Rgb<u8>, usize)], max_colors: usize, reduce: &Reduce, existing: bool,
+                metric: &Metric) -> HashMap<Rgb<u8>, Rgb<u8>> {
+    let boxes = median_cut(histogram, max_colors);
+    let mut centers: Vec<[f64; 3]> = boxes.iter().map(|colors| mean_color(colors)).collect();
+
+    if *reduce == Reduce::KMeans {
+        // Refine the median cut centers by assigning each color to its nearest center.
+        let mut assignments: Vec<usize> = vec![usize::MAX; histogram.len()];
+        for _iteration in 0..32 {
+            let mut changed = false;
+            for (position, (color, _)) in histogram.iter().enumerate() {
+                let wcolor = widen_components(color);
+                let mut best = 0;
+                let mut best_distance = f64::MAX;
+                for (center_index, center) in centers.iter().enumerate() {
+                    let distance = component_distance(&wcolor, center, metric);
+                    if distance < best_distance {
+                        best = center_index;
+                        best_distance = distance;
+                    }
+                }
+                if assignments[position] != best {
+                    assignments[position] = best;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+            for (center_index, center) in centers.iter_mut().enumerate() {
+                let members: Vec<(Rgb<u8>, usize)> = histogram.iter().zip(&assignments)
+                    .filter(|(_, assignment)| **assignment == center_index)
+                    .map(|(entry, _)| *entry)
+                    .collect();
+                if !members.is_empty() {
+                    *center = mean_color(&members);
+                }
+            }
+        }
+    }
+
+    // Snap the centers to 6-bit colors, and map each color to the nearest one.
+    let mut chosen: Vec<Rgb<u8>> = centers.iter().map(|center| {
+        let color = nearest_level_color(center, metric);
+        if existing {
+            let mut best = histogram[0].0;
+            for (candidate, _) in histogram {
+                if color_distance(&color, candidate, metric) < color_distance(&color, &best, metric) {
+                    best = *candidate;
+                }
+            }
+            best
+        } else {
+            color
+        }
+    }).collect();
+    chosen.sort_by_key(|color| color.0);
+    chosen.dedup();
+
+    let mut remap: HashMap<Rgb<u8>, Rgb<u8>> = HashMap::new();
+    for (color, _) in histogram {
+        let mut best = chosen[0];
+        for candidate in &chosen {
+            if color_distance(color, candidate, metric) < color_distance(color, &best, metric) {
+                best = *candidate;
+            }
+        }
+        remap.insert(*color, best);
+    }
+    remap
+}
+
+fn upcase_filename(path: &str) -> String {
+    let parts = path.split("/").collect::<Vec<&str>>();
+    let mut output_path = String::new();
+    for part in &parts[..parts.len()-1] {
+        output_path.push_str(part);
+        output_path.push('/');
+    }
+
+    let parts2 = parts[parts.len()-1].split(".").collect::<Vec<&str>>();
+    for part in &parts2[..parts2.len()-1] {
+        output_path.push_str(&part.to_ascii_uppercase());
+        output_path.push('.');
+    }
+    output_path.push_str("BIN");
+
+    output_path
+}
+
+// Returns the position of the top-left pixel of a trimmed file's output image, within the
+// whole (untrimmed) input image, along with the trim. Returns None for untrimmed files.
+fn trim_offset(img_file: &FileParameters) -> Option<(i32, i32, &Trim)> {
+    img_file.trimmed.as_ref().map(|trim| {
+        let (origin_x, origin_y) = image_origin(img_file, trim.width as i32, trim.height as i32);
+        (trim.x as i32 + origin_x, trim.y as i32 + origin_y, trim)
+    })
+}
+
+// Returns text as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for letter in text.chars() {
+        match letter {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            letter if (letter as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", letter as u32)),
+            letter => json.push(letter)
+        }
+    }
+    json.push('"');
+    json
+}
+
+// Writes the "METADATA.JSON" file, which describes each entry of the memory map, in a
+// machine-readable form, including the trim offset and original size of trimmed files.
+fn write_metadata(files: &[FileParameters]) {
+    let mut entries: Vec<String> = vec![];
+    let mut address: usize = 0;
+    for file in files.iter().filter(|file| !file.no_output && !file.packed) {
+        let mut fields = vec![
+            format!("\"name\": {}", json_string(&file.name())),
+            format!("\"path\": {}", json_string(&file.path)),
+            format!("\"output\": {}", json_string(&file.output_path())),
+            format!("\"address\": {}", address),
+            format!("\"size\": {}", file.size),
+            format!("\"width\": {}", file.width),
+            format!("\"height\": {}", file.height),
+            format!("\"bpp\": {}", file.bpp)
+        ];
+        if let Some((x, y, trim)) = trim_offset(file) {
+            fields.push(format!("\"offset_x\": {}", x));
+            fields.push(format!("\"offset_y\": {}", y));
+            fields.push(format!("\"original_width\": {}", trim.original_width));
+            fields.push(format!("\"original_height\": {}", trim.original_height));
+        }
+        entries.push(format!("    {{ {} }}", fields.join(", ")));
+        address += file.size;
+    }
+    let json = format!("[\n{}\n]\n", entries.join(",\n"));
+
+    let path = "METADATA.JSON";
+    match fs::File::create(path) {
+        Ok(mut file) => {
+            match file.write_all(json.as_bytes()) {
+                Ok(()) => {
+                    println!("Wrote file ({}) as {} bytes.", path, json.len());
+                },
+                Err(err) => {
+                    println!("ERROR: Cannot write metadata file ({}): {}", path, err);
+                }
+            }
+        },
+        Err(err) => {
+            println!("ERROR: Cannot open metadata file ({}): {}", path, err);
+        }
+    }
+}
+
+fn show_memory_map(files: &mut Vec<FileParameters>) {
+    println!("\nRelative Memory Map\n");
+    println!("Start  End    Size   Width Height Path/Name");
+    println!("------ ------ ------ ----- ------ ----------------------------------");
+
+    let mut address: usize = 0;
+    loop {
+        if files.is_empty() {
+            break; // no more files to arrange
+        }
+
+        let file = files[0].clone();
+
+        if file.no_output || file.packed {
+            files.remove(0);
+            continue; // skip it
+        }
+
+        let last_address = address + file.size - 1;
+        println!("{:05x}H {:05x}H {:6} {:5} {:5}  {}{}",
+            address,
+            last_address,
+            file.size,
+            file.width,
+            file.height,
+            file.name(),
+            match trim_offset(&file) {
+                Some((x, y, trim)) => format!(" (offset {},{} in {}x{})", x, y, trim.original_width, trim.original_height),
+                None => String::new()
+            });
+
+        files.remove(0);
+        address += file.size;
+    }
+}
+
+fn widen_color(color: &Rgb<u8>) -> Rgb<u8> {
+    Rgb([widen_component(color[0]), widen_component(color[1]), widen_component(color[2])])
+}
+
+fn widen_component(component: u8) -> u8 {
+    component << 6 | component << 4 | component << 2 | component
+}
+
+// Decodes a PCX file (version 5 or earlier, RLE encoded). Supported are 1 bit per pixel
+// with 1 to 4 bit planes, 2 or 4 bits per pixel with 1 plane (all using the 16-color header
+// palette), and 8 bits per pixel with 1 plane (using the 256-color palette at the end of
+// the file), 3 planes (RGB), or 4 planes (RGBA).
+fn decode_pcx(data: &[u8]) -> Result<image::DynamicImage, String> {
+    if data.len() < 128 || data[0] != 0x0A || data[2] != 1 {
+        return Err("not an RLE encoded PCX file".to_string());
+    }
+    let word = |offset: usize| data[offset] as usize | (data[offset + 1] as usize) << 8;
+    if word(8) < word(4) || word(10) < word(6) {
+        return Err("invalid image dimensions".to_string());
+    }
+    let bits = data[3] as usize;
+    let width = word(8) + 1 - word(4);
+    let height = word(10) + 1 - word(6);
+    let planes = data[65] as usize;
+    let line_size = word(66);
+    if line_size * 8 < width * bits {
+        return Err("invalid image dimensions".to_string());
+    }
+
+    // Expand the run-length encoded scan lines (each holding every plane in turn).
+    // Each pair of input bytes expands to at most 63 bytes, which also bounds the
+    // pixel count, since a line holds at least one pixel per bit.
+    let total = line_size * planes * height;
+    if total > (data.len() - 128).div_ceil(2) * 63 {
+        return Err("image data is too short".to_string());
+    }
+    let mut lines: Vec<u8> = Vec::with_capacity(total);
+    let mut position = 128;
+    while lines.len() < total && position < data.len() {
+        let byte = data[position];
+        position += 1;
+        if byte & 0xC0 == 0xC0 && position < data.len() {
+            let count = (byte & 0x3F) as usize;
+            lines.resize(lines.len() + count, data[position]);
+            position += 1;
+        } else {
+            lines.push(byte);
+        }
+    }
+    if lines.len() < total {
+        return Err("image data is too short".to_string());
+    }
+
+    // Choose the palette for indexed images.
+    let palette: Vec<[u8; 3]> = match (bits, planes) {
+        (8, 1) => {
+            if data.len() < 769 || data[data.len() - 769] != 0x0C {
+                return Err("missing 256-color palette".to_string());
+            }
+            data[data.len() - 768..].chunks(3).map(|entry| [entry[0], entry[1], entry[2]]).collect()
+        },
+        (1, 1) if data[16..64].iter().all(|component| *component == 0) => {
+            vec![[0, 0, 0], [255, 255, 255]] // monochrome, without a header palette
+        },
+        (1, 1..=4) | (2, 1) | (4, 1) => {
+            data[16..64].chunks(3).map(|entry| [entry[0], entry[1], entry[2]]).collect()
+        },
+        (8, 3) | (8, 4) => vec![],
+        _ => {
+            return Err(format!("unsupported format ({} bits per pixel, {} planes)", bits, planes));
+        }
+    };
+
+    let mut rgba = image::RgbaImage::new(width as u32, height as u32);
+    for (y, line) in lines.chunks(line_size * planes).take(height).enumerate() {
+        for x in 0..width {
+            let pixel = if palette.is_empty() {
+                let component = |plane: usize| line[plane * line_size + x];
+                let alpha = if planes == 4 { component(3) } else { 255 };
+                [component(0), component(1), component(2), alpha]
+            } else {
+                let mut index: usize = 0;
+                for plane in 0..planes {
+                    let plane_line = &line[plane * line_size..];
+                    let bit = x * bits;
+                    let value = (plane_line[bit / 8] as usize >> (8 - bits - bit % 8)) & ((1 << bits) - 1);
+                    index |= value << (plane * bits);
+                }
+                let color = palette[index];
+                [color[0], color[1], color[2], 255]
+            };
+            rgba.put_pixel(x as u32, y as u32, Rgba::<u8>(pixel));
+        }
+    }
+    if planes == 4 && bits == 8 {
+        Ok(image::DynamicImage::ImageRgba8(rgba))
+    } else {
+        Ok(image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(rgba).to_rgb8()))
+    }
+}
+
+#[cfg(test)]
+mod pcx_tests {
+    use super::*;
+
+    // Returns a 128-byte PCX header for an 8-bit, 3-plane image with the given bounds.
+    fn header(xmin: u16, ymin: u16, xmax: u16, ymax: u16, line_size: u16) -> Vec<u8> {
+        let mut data = vec![0u8; 128];
+        data[0] = 0x0A;
+        data[1] = 5;
+        data[2] = 1;
+        data[3] = 8;
+        data[4..6].copy_from_slice(&xmin.to_le_bytes());
+        data[6..8].copy_from_slice(&ymin.to_le_bytes());
+        data[8..10].copy_from_slice(&xmax.to_le_bytes());
+        data[10..12].copy_from_slice(&ymax.to_le_bytes());
+        data[65] = 3;
+        data[66..68].copy_from_slice(&line_size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn rejects_reversed_bounds() {
+        assert_eq!(decode_pcx(&header(5, 0, 2, 0, 4)).unwrap_err(), "invalid image dimensions");
+        assert_eq!(decode_pcx(&header(0, 5, 0, 2, 4)).unwrap_err(), "invalid image dimensions");
+    }
+
+    #[test]
+    fn rejects_short_lines() {
+        assert_eq!(decode_pcx(&header(0, 0, 7, 0, 4)).unwrap_err(), "invalid image dimensions");
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        assert_eq!(decode_pcx(&header(0, 0, 65534, 65535, 65535)).unwrap_err(), "image data is too short");
+    }
+
+    #[test]
+    fn decodes_true_color() {
+        let mut data = header(0, 0, 1, 0, 2);
+        // The red, green, and blue lines; values of C0H and above need a run of one (C1H).
+        data.extend_from_slice(&[0xC1, 0xFF, 0x00, 0x00, 0xC1, 0xFF, 0x00, 0x80]);
+        let img = decode_pcx(&data).unwrap().to_rgb8();
+        assert_eq!(img.dimensions(), (2, 1));
+        assert_eq!(img.get_pixel(0, 0).0, [0xFF, 0x00, 0x00]);
+        assert_eq!(img.get_pixel(1, 0).0, [0x00, 0xFF, 0x80]);
+    }
+}
+
+#[cfg(test)]
+mod aseprite_tests {
+    use super::*;
+
+    fn chunk(chunk_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut chunk = ((data.len() + 6) as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(&chunk_type.to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn frame(chunks: &[Vec<u8>], delay: u16) -> Vec<u8> {
+        let body = chunks.concat();
+        let mut frame = ((body.len() + 16) as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&0xF1FAu16.to_le_bytes());
+        frame.extend_from_slice(&(chunks.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&delay.to_le_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    // Returns an RGBA file of 2x1 pixels, with the given frames.
+    fn file(frames: &[Vec<u8>]) -> Vec<u8> {
+        let body = frames.concat();
+        let mut data = vec![0u8; 128];
+        data[0..4].copy_from_slice(&((body.len() + 128) as u32).to_le_bytes());
+        data[4..6].copy_from_slice(&0xA5E0u16.to_le_bytes());
+        data[6..8].copy_from_slice(&(frames.len() as u16).to_le_bytes());
+        data[8..10].copy_from_slice(&2u16.to_le_bytes());
+        data[10..12].copy_from_slice(&1u16.to_le_bytes());
+        data[12..14].copy_from_slice(&32u16.to_le_bytes());
+        data[14..18].copy_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    // A visible layer, at the top level.
+    fn layer() -> Vec<u8> {
+        let mut data = vec![0u8; 16];
+        data[0] = 1;
+        data[12] = 255;
+        data.extend_from_slice(&[1, 0, b'l']);
+        chunk(0x2004, &data)
+    }
+
+    // An uncompressed cel of 2x1 pixels, on the first layer.
+    fn cel(pixels: [[u8; 4]; 2]) -> Vec<u8> {
+        let mut data = vec![0u8; 16];
+        data[6] = 255;
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&pixels.concat());
+        chunk(0x2005, &data)
+    }
+
+    // A cel on the first layer, linked to the cel of the given frame.
+    fn linked_cel(frame: u16) -> Vec<u8> {
+        let mut data = vec![0u8; 16];
+        data[6] = 255;
+        data[7..9].copy_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&frame.to_le_bytes());
+        chunk(0x2005, &data)
+    }
+
+    fn tag(name: &str, first: u16, last: u16) -> Vec<u8> {
+        let mut data = 1u16.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&first.to_le_bytes());
+        data.extend_from_slice(&last.to_le_bytes());
+        data.extend_from_slice(&[0; 13]);
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        chunk(0x2018, &data)
+    }
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    #[test]
+    fn decodes_frames_and_tags() {
+        let data = file(&[
+            frame(&[layer(), tag("walk", 0, 1), cel([RED, CLEAR])], 100),
+            frame(&[cel([GREEN, RED])], 150)
+        ]);
+        let aseprite = decode_aseprite(&data).unwrap();
+        assert_eq!(aseprite.tags, vec![("walk".to_string(), 0, 1)]);
+        assert!(aseprite.palette.is_none());
+        assert_eq!(aseprite.frames.len(), 2);
+        assert_eq!(aseprite.frames[0].1, 100);
+        assert_eq!(aseprite.frames[1].1, 150);
+        assert_eq!(aseprite.frames[0].0.get_pixel(0, 0).0, RED);
+        assert_eq!(aseprite.frames[0].0.get_pixel(1, 0).0, CLEAR);
+        assert_eq!(aseprite.frames[1].0.get_pixel(0, 0).0, GREEN);
+        assert_eq!(aseprite.frames[1].0.get_pixel(1, 0).0, RED);
+    }
+
+    #[test]
+    fn decodes_linked_cel() {
+        let data = file(&[
+            frame(&[layer(), cel([RED, GREEN])], 100),
+            frame(&[linked_cel(0)], 100)
+        ]);
+        let aseprite = decode_aseprite(&data).unwrap();
+        assert_eq!(aseprite.frames[1].0.get_pixel(0, 0).0, RED);
+        assert_eq!(aseprite.frames[1].0.get_pixel(1, 0).0, GREEN);
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let data = file(&[frame(&[layer(), cel([RED, GREEN])], 100)]);
+        assert_eq!(decode_aseprite(&data[..data.len() - 1]).err().as_deref(), Some("the file is truncated"));
+    }
+
+    #[test]
+    fn rejects_oversized_palette() {
+        let mut palette = 0xFFFF_FFFFu32.to_le_bytes().to_vec();
+        palette.extend_from_slice(&0u32.to_le_bytes());
+        palette.extend_from_slice(&0u32.to_le_bytes());
+        palette.extend_from_slice(&[0; 8]);
+        palette.extend_from_slice(&[0, 0, 0, 0, 0, 255]);
+        let data = file(&[frame(&[chunk(0x2019, &palette)], 100)]);
+        assert_eq!(decode_aseprite(&data).err().as_deref(), Some("invalid palette chunk"));
+    }
+}
+
+#[cfg(test)]
+mod color_tests {
+    use super::*;
+
+    // Test pairs from Sharma, Wu, and Dalal, "The CIEDE2000 Color-Difference Formula:
+    // Implementation Notes, Supplementary Test Data, and Mathematical Observations" (2005).
+    const SHARMA_PAIRS: [([f64; 3], [f64; 3], f64); 34] = [
+        ([50.0000, 2.6772, -79.7751], [50.0000, 0.0000, -82.7485], 2.0425),
+        ([50.0000, 3.1571, -77.2803], [50.0000, 0.0000, -82.7485], 2.8615),
+        ([50.0000, 2.8361, -74.0200], [50.0000, 0.0000, -82.7485], 3.4412),
+        ([50.0000, -1.3802, -84.2814], [50.0000, 0.0000, -82.7485], 1.0000),
+        ([50.0000, -1.1848, -84.8006], [50.0000, 0.0000, -82.7485], 1.0000),
+        ([50.0000, -0.9009, -85.5211], [50.0000, 0.0000, -82.7485], 1.0000),
+        ([50.0000, 0.0000, 0.0000], [50.0000, -1.0000, 2.0000], 2.3669),
+        ([50.0000, -1.0000, 2.0000], [50.0000, 0.0000, 0.0000], 2.3669),
+        ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0009], 7.1792),
+        ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0010], 7.1792),
+        ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0011], 7.2195),
+        ([50.0000, 2.4900, -0.0010], [50.0000, -2.4900, 0.0012], 7.2195),
+        ([50.0000, -0.0010, 2.4900], [50.0000, 0.0009, -2.4900], 4.8045),
+        ([50.0000, -0.0010, 2.4900], [50.0000, 0.0010, -2.4900], 4.8045),
+        ([50.0000, -0.0010, 2.4900], [50.0000, 0.0011, -2.4900], 4.7461),
+        ([50.0000, 2.5000, 0.0000], [50.0000, 0.0000, -2.5000], 4.3065),
+        ([50.0000, 2.5000, 0.0000], [73.0000, 25.0000, -18.0000], 27.1492),
+        ([50.0000, 2.5000, 0.0000], [61.0000, -5.0000, 29.0000], 22.8977),
+        ([50.0000, 2.5000, 0.0000], [56.0000, -27.0000, -3.0000], 31.9030),
+        ([50.0000, 2.5000, 0.0000], [58.0000, 24.0000, 15.0000], 19.4535),
+        ([50.0000, 2.5000, 0.0000], [50.0000, 3.1736, 0.5854], 1.0000),
+        ([50.0000, 2.5000, 0.0000], [50.0000, 3.2972, 0.0000], 1.0000),
+        ([50.0000, 2.5000, 0.0000], [50.0000, 1.8634, 0.5757], 1.0000),
+        ([50.0000, 2.5000, 0.0000], [50.0000, 3.2592, 0.3350], 1.0000),
+        ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+        ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+        ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+        ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+        ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+        ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+        ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+        ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+        ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+        ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082)
+    ];
+
+    #[test]
+    fn delta_e_2000_matches_reference_pairs() {
+        for (lab1, lab2, expected) in SHARMA_PAIRS {
+            let difference = delta_e_2000(&lab1, &lab2);
+            assert!((difference - expected).abs() < 0.00005, "{:?} {:?}: {} (expected {})", lab1, lab2, difference, expected);
+            assert!((delta_e_2000(&lab2, &lab1) - difference).abs() < 1e-9);
+        }
+    }
+}